@@ -1,27 +1,285 @@
-use std::fs::{OpenOptions, remove_file};
+use std::fs::{File, OpenOptions, TryLockError, read_dir, read_to_string, remove_file, rename};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::path::Path;
-use std::fmt::Display;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, IsTerminal, Write as _};
+use std::path::{Path, PathBuf};
+use std::fmt::{Display, Write};
 use std::env;
+use std::process;
+use std::thread;
+use std::time;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use ansi_term::Colour;
+use ansi_term::{Colour, Style};
 use chrono::prelude::*;
-use chrono::TimeZone;
-use clap::{Arg, App, SubCommand};
-use csv::{ReaderBuilder, Writer, StringRecord};
+use chrono::{Duration, IsoWeek, TimeZone};
+use chrono_tz::Tz;
+use clap::{Arg, App, ArgGroup, SubCommand, value_t};
+use csv::{Reader, ReaderBuilder, Writer, WriterBuilder, StringRecord};
+use notify_rust::Notification;
+use serde::{Deserialize, Serialize};
 
 static TIME_FMT: &str = "%H:%M:%S";
 static DATE_FMT: &str = "%A, %B %e, %Y";
+static DAY_FMT: &str = "%Y-%m-%d";
 static DW_LOG: &str = ".dw.csv";
 static DW_TMP: &str = ".dw.tmp";
+static DW_TAGS: &str = ".dwtags";
+static DW_LOCK: &str = ".dw.lock";
+
+static LOG_HEADER: [&str; 6] = ["start", "stop", "elapsed_seconds", "description", "tags", "category"];
+
+static NOTHING_LOGGED: &str = "No deep work logged yet";
+static UNTAGGED: &str = "(untagged)";
+static UNCATEGORIZED: &str = "(uncategorized)";
+static CORRUPT_TMP: &str = "Active session file is corrupt; run `dw cancel` to reset";
+static EMPTY_TMP: &str = "Active session file is empty — run `dw cancel`";
+static CHART_WIDTH: usize = 40;
+static CHART_COLOURS: [u8; 6] = [13, 12, 10, 11, 9, 14];
+static PAUSE: &str = "PAUSE";
+static RESUME: &str = "RESUME";
+static NOTE: &str = "NOTE";
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Exit code of `status` and `stop` when no session is active.
+static NO_SESSION_EXIT: i32 = 2;
 
 static TXT_COLOUR: u8 = 13;
+/// Times to retry taking a busy lock, unless `DW_LOCK_RETRIES` says otherwise.
+static LOCK_RETRIES: u32 = 3;
+/// Milliseconds to wait before the first retry; each retry waits twice as long.
+static LOCK_BACKOFF_MS: u64 = 50;
+static BAR_WIDTH: i64 = 30;
+
+/// Prints a line to stdout unless `--quiet` was given. Errors go to stderr
+/// regardless.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Settings that control how output is rendered.
+struct Config {
+    colour: u8,
+    colored: bool,
+    time_fmt: String,
+    /// Format of the date heading a day's summary.
+    date_fmt: String,
+    /// Seconds of deep work to aim for each day.
+    daily_goal: Option<i64>,
+    /// Hour of the day from which `start` suggests resting instead.
+    warn_after_hour: Option<u32>,
+    duration_style: DurationStyle,
+    /// Tags to report under another, canonical tag.
+    tag_aliases: HashMap<String, String>,
+    /// Show only the first line of multi-line descriptions.
+    truncate: bool,
+}
+
+/// Settings read from the optional config file. Environment variables
+/// override these, and flags override both.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    colour: Option<u8>,
+    daily_goal_hours: Option<f64>,
+    time_format: Option<String>,
+    date_format: Option<String>,
+    log_path: Option<PathBuf>,
+    warn_after_hour: Option<u32>,
+    #[serde(default)]
+    tag_aliases: HashMap<String, String>,
+}
+
+/// Options given to `start`.
+struct StartOptions<'a> {
+    category: &'a str,
+    pomodoro: Option<u32>,
+    /// When the session starts.
+    at: DateTime<Local>,
+    /// Reopen the last session instead if it stopped at most this many
+    /// minutes ago with the same tags.
+    resume_within: Option<u32>,
+}
+
+/// Options given to `summary`.
+struct SummaryOptions<'a> {
+    json: bool,
+    /// Print nothing but the total seconds.
+    bare: bool,
+    tags: Vec<&'a str>,
+    by_category: bool,
+    /// How many `/` separated segments of each tag to group by.
+    depth: Option<usize>,
+    avg: bool,
+    goal: Option<i64>,
+    tz: Option<Tz>,
+    lifetime: bool,
+    /// The running session as if it stopped now, to count it too.
+    active: Option<Session>,
+    /// Only count sessions starting at or after this time of day.
+    after: Option<NaiveTime>,
+    /// Only count sessions starting before this time of day.
+    before: Option<NaiveTime>,
+}
+
+/// Options given to `stop`.
+struct StopOptions {
+    min_duration: i64,
+    round: Option<u32>,
+    notify: bool,
+    prompt: bool,
+    /// Leave the active session file in place, for debugging.
+    keep_tmp: bool,
+}
+
+/// A completed deep work session, as stored in the log.
+#[derive(Clone, Serialize)]
+struct Session {
+    start: DateTime<FixedOffset>,
+    stop: DateTime<FixedOffset>,
+    elapsed_seconds: i64,
+    description: String,
+    tags: Vec<String>,
+    category: Option<String>,
+}
+
+impl Session {
+    fn from_record(record: &StringRecord) -> Result<Session, Box<dyn Error>> {
+        let field = |i: usize| record.get(i)
+            .ok_or_else(|| format!("missing {} field", LOG_HEADER[i]));
+        let timestamp = |i: usize| -> Result<DateTime<FixedOffset>, Box<dyn Error>> {
+            let value = field(i)?;
+            DateTime::parse_from_rfc3339(value)
+                .map_err(|_| format!("invalid {} time '{}'", LOG_HEADER[i], value).into())
+        };
+
+        let elapsed = field(2)?;
+
+        Ok(Session {
+            start: timestamp(0)?,
+            stop: timestamp(1)?,
+            elapsed_seconds: elapsed.parse()
+                .map_err(|_| format!("invalid elapsed seconds '{}'", elapsed))?,
+            description: field(3)?.to_string(),
+            tags: field(4)?.split_whitespace().map(String::from).collect(),
+            // Rows logged before categories were introduced have no column.
+            category: record.get(5).filter(|c| !c.is_empty()).map(String::from),
+        })
+    }
+
+    fn to_record(&self) -> Vec<String> {
+        vec![self.start.to_rfc3339(),
+            self.stop.to_rfc3339(),
+            self.elapsed_seconds.to_string(),
+            self.description.clone(),
+            self.tags.join(" "),
+            self.category.clone().unwrap_or_default()]
+    }
+
+    fn category_label(&self) -> &str {
+        self.category.as_deref().unwrap_or(UNCATEGORIZED)
+    }
+}
+
+/// How durations are written out.
+#[derive(Clone, Copy, PartialEq)]
+enum DurationStyle {
+    /// `1 hour(s), 2 minute(s), 3 second(s)`
+    Words,
+    /// `01:02:03`
+    Clock,
+}
+
+/// How `status` reports the active session.
+#[derive(Clone, Copy, PartialEq)]
+enum StatusFormat {
+    Text,
+    /// A single uncoloured line for status bars.
+    Short,
+    Json,
+    /// Just the elapsed seconds.
+    Seconds,
+}
+
+/// The span of time a summary covers.
+enum Window {
+    Day(NaiveDate),
+    Week(IsoWeek),
+    /// The calendar month of the given day.
+    Month(NaiveDate),
+    Range(NaiveDate, NaiveDate),
+    Since(DateTime<Local>),
+}
+
+impl Window {
+    /// Whether a session started at `start` falls in the window. Its day is
+    /// taken in `tz` if given, and otherwise in the offset it was logged with.
+    fn contains(&self, start: DateTime<FixedOffset>, tz: Option<Tz>) -> bool {
+        let day = match tz {
+            Some(tz) => start.with_timezone(&tz).date_naive(),
+            None => start.date_naive(),
+        };
+
+        match *self {
+            Window::Day(d) => day == d,
+            Window::Week(week) => day.iso_week() == week,
+            Window::Month(month) => day.year() == month.year() && day.month() == month.month(),
+            Window::Range(from, to) => from <= day && day <= to,
+            Window::Since(since) => start >= since,
+        }
+    }
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = App::new("Deep Work Tracker")
             .version("0.1.0")
             .author("Siddharth Mahendraker <siddharth.mahen@gmail.com>")
             .about("A simple deep work time management tool")
+            .arg(Arg::with_name("dry-run")
+                .global(true)
+                .long("dry-run")
+                .help("Show what start and stop would do without changing any files"))
+            .arg(Arg::with_name("verbose")
+                .global(true)
+                .long("verbose")
+                .help("Print each file read, written or removed to stderr"))
+            .arg(Arg::with_name("quiet")
+                .global(true)
+                .short("q")
+                .long("quiet")
+                .help("Print nothing except errors"))
+            .arg(Arg::with_name("profile")
+                .takes_value(true)
+                .global(true)
+                .long("profile")
+                .value_name("NAME")
+                .validator(validate_name)
+                .help("Use a separate log and active session, kept in .dw.NAME.csv and .dw.NAME.tmp"))
+            .arg(Arg::with_name("duration-style")
+                .takes_value(true)
+                .global(true)
+                .long("duration-style")
+                .value_name("STYLE")
+                .possible_values(&["words", "clock"])
+                .help("Show durations as '1 hour(s), 2 minute(s), 3 second(s)' or as '01:02:03' [default: words]"))
+            .arg(Arg::with_name("time-format")
+                .takes_value(true)
+                .global(true)
+                .long("time-format")
+                .value_name("FORMAT")
+                .help("strftime format used to display times [default: %H:%M:%S]"))
+            .arg(Arg::with_name("date-format")
+                .takes_value(true)
+                .global(true)
+                .long("date-format")
+                .value_name("FORMAT")
+                .help("strftime format used for the date in summary headings [default: %A, %B %e, %Y]"))
             .subcommand(SubCommand::with_name("start")
                 .about("Start tracking a deep work session")
                 .arg(Arg::with_name("description")
@@ -32,6 +290,13 @@ fn main() -> Result<(), Box<dyn Error>> {
                     .default_value("")
                     .hide_default_value(true)
                     .help("Description attached to this deep work session"))
+                .arg(Arg::with_name("desc-file")
+                    .required(false)
+                    .takes_value(true)
+                    .long("desc-file")
+                    .value_name("PATH")
+                    .conflicts_with("description")
+                    .help("Read the description from this file"))
                 .arg(Arg::with_name("tags")
                     .required(false)
                     .multiple(true)
@@ -41,216 +306,3311 @@ fn main() -> Result<(), Box<dyn Error>> {
                     .default_value("")
                     .visible_alias("tags")
                     .hide_default_value(true)
-                    .help("Tag(s) attached to this deep work session")))
+                    .help("Tag(s) attached to this deep work session"))
+                .arg(Arg::with_name("category")
+                    .required(false)
+                    .takes_value(true)
+                    .short("c")
+                    .long("category")
+                    .default_value("")
+                    .hide_default_value(true)
+                    .help("Category of this deep work session, e.g. Work or Study"))
+                .arg(Arg::with_name("at")
+                    .required(false)
+                    .takes_value(true)
+                    .long("at")
+                    .value_name("TIME")
+                    .help("Start the session at this earlier time: HH:MM today, e.g. 'yesterday 23:30', or RFC 3339"))
+                .arg(Arg::with_name("pomodoro")
+                    .required(false)
+                    .takes_value(true)
+                    .long("pomodoro")
+                    .value_name("MINUTES")
+                    .help("Let `dw check` stop the session once it has run this long"))
+                .arg(Arg::with_name("resume-if-recent")
+                    .required(false)
+                    .takes_value(true)
+                    .long("resume-if-recent")
+                    .value_name("MINUTES")
+                    .conflicts_with("pomodoro")
+                    .help("Reopen the last session instead if it had the same tags and stopped at most this long ago"))
+                .arg(name_arg()))
+            .subcommand(SubCommand::with_name("resume-last")
+                .about("Start a new session with the last session's description, tags and category")
+                .arg(name_arg()))
             .subcommand(SubCommand::with_name("stop")
-                .about("Stop tracking the current deep work session"))
+                .about("Stop tracking the current deep work session")
+                .arg(Arg::with_name("truncate")
+                    .long("truncate")
+                    .help("Show only the first line of a multi-line description"))
+                .arg(Arg::with_name("min-duration")
+                    .required(false)
+                    .takes_value(true)
+                    .long("min-duration")
+                    .value_name("SECONDS")
+                    .default_value("0")
+                    .help("Discard the session instead of logging it if it is shorter than this"))
+                .arg(Arg::with_name("notify")
+                    .long("notify")
+                    .help("Show a desktop notification summarizing the session"))
+                .arg(Arg::with_name("at")
+                    .required(false)
+                    .takes_value(true)
+                    .long("at")
+                    .value_name("TIME")
+                    .help("Stop the session at this earlier time: HH:MM today, e.g. 'yesterday 23:30', or RFC 3339"))
+                .arg(Arg::with_name("round")
+                    .required(false)
+                    .takes_value(true)
+                    .long("round")
+                    .value_name("MINUTES")
+                    .help("Round the logged duration up to a multiple of this many minutes"))
+                .arg(Arg::with_name("prompt")
+                    .long("prompt")
+                    .help("Ask for a description if the session has none and stdin is a terminal"))
+                .arg(Arg::with_name("keep-tmp")
+                    .long("keep-tmp")
+                    .alias("no-tmp-cleanup")
+                    .hidden(true)
+                    .help("Don't remove the active session file once the session is logged"))
+                .arg(name_arg()))
+            .subcommand(SubCommand::with_name("undo")
+                .about("Reopen the most recently stopped deep work session")
+                .arg(name_arg()))
+            .subcommand(SubCommand::with_name("check")
+                .about("Stop the current session if its pomodoro is over, for running from cron")
+                .arg(Arg::with_name("notify")
+                    .long("notify")
+                    .help("Show a desktop notification if the session is stopped"))
+                .arg(name_arg()))
+            .subcommand(SubCommand::with_name("cancel")
+                .about("Abandon the current deep work session without logging it")
+                .arg(name_arg()))
+            .subcommand(SubCommand::with_name("pause")
+                .about("Pause the current deep work session")
+                .arg(name_arg()))
+            .subcommand(SubCommand::with_name("resume")
+                .about("Resume the paused deep work session")
+                .arg(name_arg()))
             .subcommand(SubCommand::with_name("status")
-                .about("Get the status of the current deep work session"))
+                .about("Get the status of the current deep work session")
+                .arg(name_arg())
+                .arg(Arg::with_name("truncate")
+                    .long("truncate")
+                    .help("Show only the first line of a multi-line description"))
+                .arg(Arg::with_name("all")
+                    .long("all")
+                    .conflicts_with("name")
+                    .help("Show every active session"))
+                .arg(Arg::with_name("short")
+                    .long("short")
+                    .conflicts_with("all")
+                    .help("Print a single uncoloured line for status bars, or nothing if no session is active"))
+                .arg(Arg::with_name("json")
+                    .long("json")
+                    .conflicts_with_all(&["all", "short"])
+                    .help("Print the session as a JSON object"))
+                .arg(Arg::with_name("seconds")
+                    .long("seconds")
+                    .conflicts_with_all(&["all", "short", "json"])
+                    .help("Print only the elapsed seconds, or 0 if no session is active"))
+                .arg(Arg::with_name("watch")
+                    .long("watch")
+                    .conflicts_with_all(&["all", "short", "json", "seconds"])
+                    .help("Redraw the status every second until interrupted or the session stops"))
+                .arg(Arg::with_name("max-hours")
+                    .takes_value(true)
+                    .long("max-hours")
+                    .value_name("HOURS")
+                    .default_value("8")
+                    .help("Warn about sessions that have run longer than this")))
+            .subcommand(SubCommand::with_name("note")
+                .about("Add a timestamped note to the current deep work session")
+                .arg(Arg::with_name("text")
+                    .required(true)
+                    .help("The note; it is added to the description when the session stops"))
+                .arg(name_arg()))
+            .subcommand(SubCommand::with_name("tag")
+                .about("Change the tags of the current deep work session")
+                .arg(Arg::with_name("add")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true)
+                    .long("add")
+                    .value_name("TAG")
+                    .help("Add this tag (repeatable)"))
+                .arg(Arg::with_name("remove")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true)
+                    .long("remove")
+                    .value_name("TAG")
+                    .help("Remove this tag (repeatable)"))
+                .group(ArgGroup::with_name("change")
+                    .args(&["add", "remove"])
+                    .multiple(true)
+                    .required(true))
+                .arg(name_arg()))
             .subcommand(SubCommand::with_name("summary")
-                .about("Summarize today's deep work"))
+                .about("Summarize today's deep work")
+                .arg(Arg::with_name("json")
+                    .long("json")
+                    .help("Print the summary as a JSON object, labelled with its date, week, month, range or start"))
+                .arg(Arg::with_name("bare")
+                    .long("bare")
+                    .conflicts_with("json")
+                    .help("Print only the total seconds"))
+                .arg(Arg::with_name("week")
+                    .long("week")
+                    .conflicts_with_all(&["from", "to", "since"])
+                    .help("Summarize the current week instead of today"))
+                .arg(Arg::with_name("month")
+                    .takes_value(true)
+                    .min_values(0)
+                    .long("month")
+                    .value_name("YYYY-MM")
+                    .conflicts_with_all(&["week", "from", "to", "since"])
+                    .help("Summarize the current month, or the given one, instead of today"))
+                .arg(Arg::with_name("since")
+                    .takes_value(true)
+                    .long("since")
+                    .value_name("SPAN")
+                    .conflicts_with_all(&["from", "to"])
+                    .help("Summarize sessions started in the last SPAN, e.g. 30m, 4h or 3d"))
+                .arg(Arg::with_name("from")
+                    .takes_value(true)
+                    .long("from")
+                    .value_name("DATE")
+                    .help("Summarize from this date (inclusive): YYYY-MM-DD, today, yesterday or a weekday"))
+                .arg(Arg::with_name("to")
+                    .takes_value(true)
+                    .long("to")
+                    .value_name("DATE")
+                    .help("Summarize up to this date (inclusive), in the same forms as --from"))
+                .arg(Arg::with_name("tags")
+                    .required(false)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true)
+                    .short("t")
+                    .long("tag")
+                    .help("Only count sessions with this tag (repeatable)"))
+                .arg(Arg::with_name("avg")
+                    .long("avg")
+                    .help("Also show the average session length"))
+                .arg(Arg::with_name("after")
+                    .takes_value(true)
+                    .long("after")
+                    .value_name("HH:MM")
+                    .help("Only count sessions that started at or after this time of day"))
+                .arg(Arg::with_name("before")
+                    .takes_value(true)
+                    .long("before")
+                    .value_name("HH:MM")
+                    .help("Only count sessions that started before this time of day; with --after, may wrap past midnight"))
+                .arg(Arg::with_name("include-active")
+                    .long("include-active")
+                    .help("Also count the running session's time so far"))
+                .arg(Arg::with_name("no-lifetime")
+                    .long("no-lifetime")
+                    .help("Don't show the all-time total"))
+                .arg(Arg::with_name("by-category")
+                    .long("by-category")
+                    .help("Break the total down by category instead of by tag"))
+                .arg(Arg::with_name("depth")
+                    .takes_value(true)
+                    .long("depth")
+                    .value_name("N")
+                    .conflicts_with("by-category")
+                    .help("Group tags like work/client-a by their first N segments"))
+                .arg(Arg::with_name("tz")
+                    .takes_value(true)
+                    .long("tz")
+                    .value_name("ZONE")
+                    .help("Decide which day each session fell on in this IANA time zone, e.g. Europe/Paris"))
+                .arg(Arg::with_name("goal")
+                    .takes_value(true)
+                    .long("goal")
+                    .value_name("HOURS")
+                    .help("Show progress toward a goal of this many hours over the summarized period [env: DW_DAILY_GOAL, for a day]")))
+            .subcommand(SubCommand::with_name("annotate")
+                .about("Add a comment row to the log, which every command skips")
+                .arg(Arg::with_name("text")
+                    .required(true)
+                    .help("Text of the comment")))
+            .subcommand(SubCommand::with_name("last")
+                .about("Show the most recent completed deep work session"))
+            .subcommand(SubCommand::with_name("list")
+                .about("List completed deep work sessions, most recent first")
+                .arg(Arg::with_name("limit")
+                    .required(false)
+                    .takes_value(true)
+                    .short("n")
+                    .long("limit")
+                    .help("Only show the N most recent sessions, or the N oldest with --reverse"))
+                .arg(Arg::with_name("reverse")
+                    .long("reverse")
+                    .help("List the oldest sessions first"))
+                .arg(Arg::with_name("width")
+                    .required(false)
+                    .takes_value(true)
+                    .long("width")
+                    .value_name("N")
+                    .default_value("40")
+                    .help("Shorten descriptions longer than N characters, or 0 to show them in full")))
+            .subcommand(SubCommand::with_name("export")
+                .about("Export every completed deep work session")
+                .arg(Arg::with_name("json")
+                    .long("json")
+                    .help("Export as a JSON array"))
+                .arg(Arg::with_name("ical")
+                    .long("ical")
+                    .help("Export as an iCalendar file with one event per session"))
+                .arg(Arg::with_name("csv")
+                    .long("csv")
+                    .help("Export as CSV with a header row, for spreadsheets"))
+                .arg(Arg::with_name("output")
+                    .takes_value(true)
+                    .short("o")
+                    .long("output")
+                    .value_name("PATH")
+                    .help("Write the export to this file instead of stdout"))
+                .arg(Arg::with_name("force")
+                    .long("force")
+                    .requires("output")
+                    .help("Overwrite the output file if it already exists"))
+                .arg(delimiter_arg()
+                    .requires("csv"))
+                .group(ArgGroup::with_name("format")
+                    .args(&["json", "ical", "csv"])
+                    .required(true)))
+            .subcommand(SubCommand::with_name("import")
+                .about("Merge the sessions from another log into this one")
+                .arg(Arg::with_name("path")
+                    .required(true)
+                    .help("Path of the log to import"))
+                .arg(delimiter_arg()))
+            .subcommand(SubCommand::with_name("delete")
+                .about("Delete a completed deep work session")
+                .arg(Arg::with_name("index")
+                    .required(true)
+                    .help("Index of the session, as shown by list")))
+            .subcommand(SubCommand::with_name("merge")
+                .about("Merge two adjacent completed deep work sessions")
+                .arg(Arg::with_name("first")
+                    .required(true)
+                    .help("Index of the first session, as shown by list"))
+                .arg(Arg::with_name("second")
+                    .required(true)
+                    .help("Index of the second session, next to the first"))
+                .arg(Arg::with_name("gap")
+                    .takes_value(true)
+                    .long("gap")
+                    .value_name("POLICY")
+                    .possible_values(&["exclude", "include"])
+                    .default_value("exclude")
+                    .help("Whether the time between the sessions counts as worked")))
+            .subcommand(SubCommand::with_name("split")
+                .about("Split a completed deep work session in two")
+                .arg(Arg::with_name("index")
+                    .required(true)
+                    .help("Index of the session, as shown by list"))
+                .arg(Arg::with_name("at")
+                    .required(true)
+                    .takes_value(true)
+                    .long("at")
+                    .value_name("TIME")
+                    .help("Where to split: HH:MM on the day the session started, or RFC 3339"))
+                .arg(Arg::with_name("description")
+                    .takes_value(true)
+                    .short("d")
+                    .long("desc")
+                    .help("Description for the second part [default: the original's]"))
+                .arg(Arg::with_name("tags")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true)
+                    .short("t")
+                    .long("tag")
+                    .visible_alias("tags")
+                    .help("Tags for the second part (repeatable) [default: the original's]")))
+            .subcommand(SubCommand::with_name("report")
+                .about("Show daily deep work totals for the past week")
+                .arg(Arg::with_name("days")
+                    .required(false)
+                    .takes_value(true)
+                    .long("days")
+                    .value_name("N")
+                    .default_value("7")
+                    .help("Number of days to show, ending today")))
+            .subcommand(SubCommand::with_name("chart")
+                .about("Chart today's deep work per tag")
+                .arg(Arg::with_name("week")
+                    .long("week")
+                    .help("Chart the current week instead of today"))
+                .arg(Arg::with_name("month")
+                    .long("month")
+                    .conflicts_with("week")
+                    .help("Chart the current month instead of today")))
+            .subcommand(SubCommand::with_name("top")
+                .about("Rank tags by total deep work")
+                .arg(Arg::with_name("week")
+                    .long("week")
+                    .help("Only count the current week"))
+                .arg(Arg::with_name("month")
+                    .long("month")
+                    .conflicts_with("week")
+                    .help("Only count the current month"))
+                .arg(Arg::with_name("limit")
+                    .takes_value(true)
+                    .short("n")
+                    .long("limit")
+                    .value_name("N")
+                    .help("Only show the top N tags")))
+            .subcommand(SubCommand::with_name("stats")
+                .about("Report lifetime deep work totals, streaks and averages"))
+            .subcommand(SubCommand::with_name("doctor")
+                .about("Check the log for rows that are broken or inconsistent")
+                .arg(Arg::with_name("fix")
+                    .long("fix")
+                    .help("Recompute negative elapsed times from start and stop"))
+                .arg(Arg::with_name("all")
+                    .long("all")
+                    .requires("fix")
+                    .help("With --fix, recompute every elapsed time that doesn't match start and stop, dropping pauses and rounding")))
+            .subcommand(SubCommand::with_name("edit")
+                .about("Edit the most recent completed deep work session")
+                .arg(Arg::with_name("description")
+                    .takes_value(true)
+                    .short("d")
+                    .long("desc")
+                    .help("New description for the session"))
+                .arg(Arg::with_name("tags")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true)
+                    .short("t")
+                    .long("tag")
+                    .visible_alias("tags")
+                    .help("Replace the session's tags with these (repeatable)"))
+                .group(ArgGroup::with_name("change")
+                    .args(&["description", "tags"])
+                    .multiple(true)
+                    .required(true)))
             .get_matches();
 
-    let home = env::var("HOME")
-        .expect("Failed to access HOME environment variable");
-    let log_path = Path::new(&home).join(DW_LOG);
-    let tmp_path = Path::new(&home).join(DW_TMP);
+    VERBOSE.store(matches.is_present("verbose"), Ordering::Relaxed);
+    QUIET.store(matches.is_present("quiet"), Ordering::Relaxed);
+
+    let file = load_file_config()?;
+
+    let home = || dirs::home_dir()
+        .ok_or("Could not determine your home directory; set DW_LOG_PATH instead");
+    let dir = match (env::var_os("DW_LOG_PATH"), file.log_path) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(dir)) => match dir.strip_prefix("~") {
+            Ok(rest) => home()?.join(rest),
+            Err(_) => dir,
+        },
+        (None, None) => home()?,
+    };
+    let profile = matches.value_of("profile");
+    let log_path = log_file(&dir, profile);
+    let name = matches.subcommand().1.and_then(|sub| sub.value_of("name"));
+    let tmp_path = tmp_file(&dir, profile, name);
+
+    let cfg = Config {
+        colour: env::var("DW_COLOUR").ok()
+            .and_then(|c| c.parse().ok())
+            .or(file.colour)
+            .unwrap_or(TXT_COLOUR),
+        colored: env::var_os("NO_COLOR").is_none(),
+        time_fmt: match matches.value_of("time-format") {
+            Some(fmt) => fmt.to_string(),
+            None => env::var("DW_TIME_FMT").ok()
+                .or(file.time_format)
+                .unwrap_or_else(|| TIME_FMT.to_string()),
+        },
+        date_fmt: match matches.value_of("date-format") {
+            Some(fmt) => fmt.to_string(),
+            None => env::var("DW_DATE_FMT").ok()
+                .or(file.date_format)
+                .unwrap_or_else(|| DATE_FMT.to_string()),
+        },
+        daily_goal: daily_goal(file.daily_goal_hours)?,
+        warn_after_hour: match file.warn_after_hour {
+            Some(hour) if hour > 23 => return Err(format!(
+                "Invalid warn_after_hour {} in the config file, expected an hour from 0 to 23", hour).into()),
+            hour => hour,
+        },
+        duration_style: match matches.value_of("duration-style") {
+            Some("clock") => DurationStyle::Clock,
+            _ => DurationStyle::Words,
+        },
+        tag_aliases: file.tag_aliases,
+        truncate: matches.subcommand().1.is_some_and(|sub| sub.is_present("truncate")),
+    };
+
+    // chrono only reports a bad format string when it is rendered, so try it
+    // once up front rather than failing half way through the output.
+    for (kind, fmt) in &[("time", &cfg.time_fmt), ("date", &cfg.date_fmt)] {
+        if write!(String::new(), "{}", Local::now().format(fmt)).is_err() {
+            return Err(format!("Invalid {} format '{}'", kind, fmt).into());
+        }
+    }
 
     let log_path_str = log_path.to_str()
         .expect("Failed to convert log path to string");
     let tmp_path_str = tmp_path.to_str()
         .expect("Failed to convert tmp path to string");
 
+    let dry_run = matches.is_present("dry-run");
+
+    // Commands that change the log or an active session hold the lock until
+    // they finish, so two started at once can't both pass their checks. Those
+    // that honour --dry-run change nothing then, so they don't even create
+    // the lock file. `start` takes it itself, in start_session.
+    let mutating = ["resume-last", "stop", "check", "undo", "cancel", "pause",
+        "resume", "note", "tag", "import", "delete", "edit", "split", "merge", "annotate", "doctor"];
+    let previewable = ["resume-last", "stop", "check"];
+    let _lock = match matches.subcommand_name() {
+        Some(sub) if dry_run && previewable.contains(&sub) => None,
+        Some(sub) if mutating.contains(&sub) => Some(lock(&dir, lock_retries()?)?),
+        _ => None,
+    };
+
     if let Some(start) = matches.subcommand_matches("start") {
-        let desc = start.value_of("description").unwrap();
+        let contents;
+        let desc = match start.value_of("desc-file") {
+            Some(path) => {
+                verbose("reading description from", path);
+                contents = read_to_string(path)
+                    .map_err(|e| format!("Failed to read description from {}: {}", path, e))?;
+                contents.trim()
+            }
+            None => start.value_of("description").unwrap(),
+        };
         let tags: Vec<_> = start.values_of("tags").unwrap().collect();
-        handle_start(tmp_path_str, desc, tags)?;
-    } else if let Some(_) = matches.subcommand_matches("stop") {
-        handle_stop(log_path_str, tmp_path_str)?;
-    } else if let Some(_) = matches.subcommand_matches("status") {
-        handle_status(tmp_path_str)?;
-    } else if let Some(_) = matches.subcommand_matches("summary") {
-        handle_summary(log_path_str)?;
+        let category = start.value_of("category").unwrap();
+        let pomodoro = if start.is_present("pomodoro") {
+            let minutes = value_t!(start, "pomodoro", u32).unwrap_or_else(|e| e.exit());
+            if minutes == 0 {
+                return Err("--pomodoro must be at least 1 minute".into());
+            }
+            Some(minutes)
+        } else {
+            None
+        };
+        let at = match start.value_of("at") {
+            Some(at) => parse_time(at)?,
+            None => Local::now(),
+        };
+        let resume_within = if start.is_present("resume-if-recent") {
+            Some(value_t!(start, "resume-if-recent", u32).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        };
+        let opts = StartOptions { category, pomodoro, at, resume_within };
+        start_session(&cfg, log_path_str, tmp_path_str, desc, tags, &opts, dry_run)?;
+    } else if matches.subcommand_matches("resume-last").is_some() {
+        handle_resume_last(&cfg, log_path_str, tmp_path_str, dry_run)?;
+    } else if let Some(stop) = matches.subcommand_matches("stop") {
+        let min_duration = value_t!(stop, "min-duration", i64).unwrap_or_else(|e| e.exit());
+        let round = if stop.is_present("round") {
+            let minutes = value_t!(stop, "round", u32).unwrap_or_else(|e| e.exit());
+            if minutes == 0 {
+                return Err("--round must be at least 1 minute".into());
+            }
+            Some(minutes)
+        } else {
+            None
+        };
+        let opts = StopOptions {
+            min_duration,
+            round,
+            notify: stop.is_present("notify"),
+            prompt: stop.is_present("prompt"),
+            keep_tmp: stop.is_present("keep-tmp"),
+        };
+        let at = match stop.value_of("at") {
+            Some(at) => parse_time(at)?,
+            None => Local::now(),
+        };
+        if !handle_stop(&cfg, log_path_str, tmp_path_str, &opts, at, dry_run)? {
+            process::exit(NO_SESSION_EXIT);
+        }
+    } else if matches.subcommand_matches("undo").is_some() {
+        handle_undo(&cfg, log_path_str, tmp_path_str)?;
+    } else if let Some(check) = matches.subcommand_matches("check") {
+        handle_check(&cfg, log_path_str, tmp_path_str, check.is_present("notify"), dry_run)?;
+    } else if matches.subcommand_matches("cancel").is_some() {
+        handle_cancel(tmp_path_str)?;
+    } else if matches.subcommand_matches("pause").is_some() {
+        handle_pause(&cfg, tmp_path_str)?;
+    } else if matches.subcommand_matches("resume").is_some() {
+        handle_resume(&cfg, tmp_path_str)?;
+    } else if let Some(note) = matches.subcommand_matches("note") {
+        handle_note(&cfg, tmp_path_str, note.value_of("text").unwrap())?;
+    } else if let Some(status) = matches.subcommand_matches("status") {
+        let max_hours = value_t!(status, "max-hours", f64).unwrap_or_else(|e| e.exit());
+        let active = if status.is_present("all") {
+            handle_status_all(&cfg, &dir, profile, max_hours)?
+        } else if status.is_present("watch") {
+            handle_status_watch(&cfg, tmp_path_str, max_hours)?
+        } else {
+            let format = if status.is_present("short") {
+                StatusFormat::Short
+            } else if status.is_present("json") {
+                StatusFormat::Json
+            } else if status.is_present("seconds") {
+                StatusFormat::Seconds
+            } else {
+                StatusFormat::Text
+            };
+            handle_status(&cfg, tmp_path_str, format, max_hours)?
+        };
+        if !active {
+            process::exit(NO_SESSION_EXIT);
+        }
+    } else if let Some(tag) = matches.subcommand_matches("tag") {
+        let add: Vec<_> = tag.values_of("add").unwrap_or_default().collect();
+        let remove: Vec<_> = tag.values_of("remove").unwrap_or_default().collect();
+        handle_tag(tmp_path_str, add, remove)?;
+    } else if let Some(summary) = matches.subcommand_matches("summary") {
+        let tz = match summary.value_of("tz") {
+            Some(tz) => Some(tz.parse::<Tz>()
+                .map_err(|_| format!("Unknown time zone '{}', expected e.g. Europe/Paris", tz))?),
+            None => None,
+        };
+        let today = match tz {
+            Some(tz) => Utc::now().with_timezone(&tz).date_naive(),
+            None => Local::now().date_naive(),
+        };
+        let window = if summary.is_present("week") {
+            Window::Week(today.iso_week())
+        } else if summary.is_present("month") {
+            match summary.value_of("month") {
+                Some(month) => Window::Month(parse_month(month)?),
+                None => Window::Month(today),
+            }
+        } else if let Some(since) = summary.value_of("since") {
+            Window::Since(Local::now() - parse_span(since)?)
+        } else if summary.is_present("from") || summary.is_present("to") {
+            let from = match summary.value_of("from") {
+                Some(from) => parse_date(from)?,
+                None => DateTime::UNIX_EPOCH.date_naive(),
+            };
+            let to = match summary.value_of("to") {
+                Some(to) => parse_date(to)?,
+                None => today,
+            };
+            Window::Range(from, to)
+        } else {
+            Window::Day(today)
+        };
+        let tags: Vec<_> = summary.values_of("tags").unwrap_or_default().collect();
+        // --goal is measured against whichever window was picked, but the
+        // configured goal is a daily one, so it only applies to a day.
+        let goal = match (summary.value_of("goal"), &window) {
+            (Some(goal), _) => Some(parse_goal(goal)?),
+            (None, Window::Day(_)) => cfg.daily_goal,
+            (None, _) => None,
+        };
+        let depth = if summary.is_present("depth") {
+            let depth = value_t!(summary, "depth", usize).unwrap_or_else(|e| e.exit());
+            if depth == 0 {
+                return Err("--depth must be at least 1".into());
+            }
+            Some(depth)
+        } else {
+            None
+        };
+        let opts = SummaryOptions {
+            json: summary.is_present("json"),
+            bare: summary.is_present("bare"),
+            tags,
+            by_category: summary.is_present("by-category"),
+            depth,
+            avg: summary.is_present("avg"),
+            goal,
+            tz,
+            lifetime: !summary.is_present("no-lifetime"),
+            active: if summary.is_present("include-active") {
+                active_session(tmp_path_str)?
+            } else {
+                None
+            },
+            after: summary.value_of("after").map(parse_clock).transpose()?,
+            before: summary.value_of("before").map(parse_clock).transpose()?,
+        };
+        handle_summary(&cfg, log_path_str, window, &opts)?;
+    } else if let Some(annotate) = matches.subcommand_matches("annotate") {
+        handle_annotate(log_path_str, annotate.value_of("text").unwrap())?;
+    } else if matches.subcommand_matches("last").is_some() {
+        handle_last(&cfg, log_path_str)?;
+    } else if let Some(list) = matches.subcommand_matches("list") {
+        let limit = if list.is_present("limit") {
+            Some(value_t!(list, "limit", usize).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        };
+        let width = value_t!(list, "width", usize).unwrap_or_else(|e| e.exit());
+        handle_list(&cfg, log_path_str, limit, width, list.is_present("reverse"))?;
+    } else if let Some(report) = matches.subcommand_matches("report") {
+        let days = value_t!(report, "days", u32).unwrap_or_else(|e| e.exit());
+        handle_report(&cfg, log_path_str, days)?;
+    } else if let Some(chart) = matches.subcommand_matches("chart") {
+        let today = Local::now().date_naive();
+        let window = if chart.is_present("week") {
+            Window::Week(today.iso_week())
+        } else if chart.is_present("month") {
+            Window::Month(today)
+        } else {
+            Window::Day(today)
+        };
+        handle_chart(&cfg, log_path_str, window)?;
+    } else if let Some(top) = matches.subcommand_matches("top") {
+        let today = Local::now().date_naive();
+        let window = if top.is_present("week") {
+            Some(Window::Week(today.iso_week()))
+        } else if top.is_present("month") {
+            Some(Window::Month(today))
+        } else {
+            None
+        };
+        let limit = if top.is_present("limit") {
+            Some(value_t!(top, "limit", usize).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        };
+        handle_top(&cfg, log_path_str, window, limit)?;
+    } else if let Some(doctor) = matches.subcommand_matches("doctor") {
+        handle_doctor(log_path_str, doctor.is_present("fix"), doctor.is_present("all"))?;
+    } else if matches.subcommand_matches("stats").is_some() {
+        handle_stats(&cfg, log_path_str)?;
+    } else if let Some(export) = matches.subcommand_matches("export") {
+        let mut out = match export.value_of("output") {
+            Some(path) => output_file(path, export.is_present("force"))?,
+            None => stdout(),
+        };
+        if export.is_present("ical") {
+            handle_export_ical(log_path_str, &mut out)?;
+        } else if export.is_present("csv") {
+            let delimiter = export.value_of("delimiter").map_or(Ok(b','), parse_delimiter)?;
+            handle_export_csv(log_path_str, &mut out, delimiter)?;
+        } else {
+            handle_export(log_path_str, &mut out)?;
+        }
+        out.flush()?;
+    } else if let Some(merge) = matches.subcommand_matches("merge") {
+        let first = value_t!(merge, "first", usize).unwrap_or_else(|e| e.exit());
+        let second = value_t!(merge, "second", usize).unwrap_or_else(|e| e.exit());
+        if first.max(second) - first.min(second) != 1 {
+            return Err(format!("Sessions {} and {} are not adjacent in the log", first, second).into());
+        }
+        handle_merge(&cfg, log_path_str, first.min(second), merge.value_of("gap") == Some("include"))?;
+    } else if let Some(split) = matches.subcommand_matches("split") {
+        let index = value_t!(split, "index", usize).unwrap_or_else(|e| e.exit());
+        let tags = split.values_of("tags").map(|tags| tags.filter(|t| !t.is_empty()).collect());
+        handle_split(&cfg, log_path_str, index, split.value_of("at").unwrap(),
+            split.value_of("description"), tags)?;
+    } else if let Some(import) = matches.subcommand_matches("import") {
+        let delimiter = import.value_of("delimiter").map_or(Ok(b','), parse_delimiter)?;
+        handle_import(log_path_str, import.value_of("path").unwrap(), delimiter)?;
+    } else if let Some(delete) = matches.subcommand_matches("delete") {
+        let index = value_t!(delete, "index", usize).unwrap_or_else(|e| e.exit());
+        handle_delete(&cfg, log_path_str, index)?;
+    } else if let Some(edit) = matches.subcommand_matches("edit") {
+        let tags = edit.values_of("tags").map(|tags| tags.filter(|t| !t.is_empty()).collect());
+        handle_edit(&cfg, log_path_str, edit.value_of("description"), tags)?;
     }
 
     Ok(())
 }
 
-fn handle_summary(log_path: &str) -> Result<(), Box<dyn Error>> {
-    let file = OpenOptions::new()
-        .read(true)
-        .open(log_path)?;
+fn name_arg() -> Arg<'static, 'static> {
+    Arg::with_name("name")
+        .required(false)
+        .takes_value(true)
+        .long("name")
+        .validator(validate_name)
+        .help("Name of the session, to run several at once")
+}
 
-    let mut reader = ReaderBuilder::new()
-        .has_headers(false)
-        .from_reader(file);
+fn delimiter_arg() -> Arg<'static, 'static> {
+    Arg::with_name("delimiter")
+        .takes_value(true)
+        .long("delimiter")
+        .value_name("DELIM")
+        .help("Field separator: comma, tab, semicolon or a single character [default: comma]")
+}
 
-    let iter = reader.records();
+/// Session and profile names end up in file names, so keep them simple.
+fn validate_name(name: String) -> Result<(), String> {
+    if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        Ok(())
+    } else {
+        Err("names may only contain letters, digits, '-' and '_'".to_string())
+    }
+}
 
-    let mut total_dw_time = 0;
+/// Reads `dw/config.toml` from the user's config directory, e.g.
+/// `~/.config/dw/config.toml`. A missing file leaves everything at its
+/// default.
+fn load_file_config() -> Result<FileConfig, Box<dyn Error>> {
+    let path = match dirs::config_dir() {
+        Some(dir) => dir.join("dw").join("config.toml"),
+        None => return Ok(FileConfig::default()),
+    };
 
-    for sess in iter {
-        let record = sess.unwrap();
-        let start = DateTime::parse_from_rfc3339(&record[0])?;
-        let duration : i32 = (&record[2]).parse().unwrap();
-        if start.date() == Local::now().date() {
-            total_dw_time += duration;
+    verbose("reading config", &path);
+    match read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e).into()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(FileConfig::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// How many times to retry a busy lock: `DW_LOCK_RETRIES`, or `LOCK_RETRIES`.
+fn lock_retries() -> Result<u32, Box<dyn Error>> {
+    match env::var("DW_LOCK_RETRIES") {
+        Ok(n) => Ok(n.parse().map_err(|_| format!("Invalid DW_LOCK_RETRIES '{}', expected a whole number", n))?),
+        Err(_) => Ok(LOCK_RETRIES),
+    }
+}
+
+/// Takes an exclusive lock on the lock file in `dir`. If another `dw` holds
+/// it, tries again up to `retries` times, backing off from `LOCK_BACKOFF_MS`
+/// and doubling each time, before giving up. The lock is released when the
+/// file is dropped.
+fn lock(dir: &Path, retries: u32) -> Result<File, Box<dyn Error>> {
+    let path = dir.join(DW_LOCK);
+    verbose("locking", &path);
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+
+    let mut backoff = LOCK_BACKOFF_MS;
+    for attempt in 0..=retries {
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(TryLockError::WouldBlock) if attempt < retries => {
+                thread::sleep(time::Duration::from_millis(backoff));
+                backoff *= 2;
+            }
+            Err(TryLockError::WouldBlock) => break,
+            Err(TryLockError::Error(e)) => return Err(e.into()),
         }
     }
 
-    let hrs = total_dw_time/3600;
-    let minutes = (total_dw_time/60) - 60*hrs;
-    let seconds = total_dw_time - 60*minutes - 3600*hrs;
+    Err(format!("{} is locked by another dw; try again in a moment", path.display()).into())
+}
 
-    let now = Local::now();
+/// The session log for `profile`, or for the default profile.
+fn log_file(dir: &Path, profile: Option<&str>) -> PathBuf {
+    match profile {
+        Some(profile) => dir.join(format!(".dw.{}.csv", profile)),
+        None => dir.join(DW_LOG),
+    }
+}
 
-    println!("Deep work summary for {}:", now.format(DATE_FMT).to_string());
-    println!("{} hour(s) {} minute(s) {} seconds(s)",
-        Colour::Fixed(TXT_COLOUR).paint(hrs.to_string()),
-        Colour::Fixed(TXT_COLOUR).paint(minutes.to_string()),
-        Colour::Fixed(TXT_COLOUR).paint(seconds.to_string()));
+/// The active session file for the session called `name`, or for the
+/// unnamed session, in `profile`. Names and profiles can't contain dots, so
+/// the four kinds of file have different numbers of parts and can't clash:
+/// `.dw.tmp`, `.dw.PROFILE.tmp`, `.dw.session.NAME.tmp` and
+/// `.dw.PROFILE.session.NAME.tmp`.
+fn tmp_file(dir: &Path, profile: Option<&str>, name: Option<&str>) -> PathBuf {
+    match (profile, name) {
+        (Some(profile), Some(name)) => dir.join(format!(".dw.{}.session.{}.tmp", profile, name)),
+        (Some(profile), None) => dir.join(format!(".dw.{}.tmp", profile)),
+        (None, Some(name)) => dir.join(format!(".dw.session.{}.tmp", name)),
+        (None, None) => dir.join(DW_TMP),
+    }
+}
 
-    Ok(())
+/// Opens the session log for reading, skipping the header row. Logs written
+/// before the header was introduced have none, so every row is a session.
+///
+/// Returns `None` if nothing has been logged yet.
+fn log_reader(log_path: &str) -> Result<Option<Reader<BufReader<File>>>, Box<dyn Error>> {
+    log_reader_delimited(log_path, b',')
 }
 
-fn handle_start(tmp_path: &str, desc: &str, tags: Vec<&str>) -> Result<(), Box<dyn Error>> {
-    let path = Path::new(tmp_path);
+/// Like `log_reader`, for a log whose fields are separated by `delimiter`.
+fn log_reader_delimited(log_path: &str, delimiter: u8) -> Result<Option<Reader<BufReader<File>>>, Box<dyn Error>> {
+    verbose("reading log", log_path);
+    let file = match OpenOptions::new().read(true).open(log_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
 
-    if path.is_file() {
-        println!("Another deep work session is active");
-        return Ok(());
+    let mut buf = BufReader::new(file);
+    let has_headers = buf.fill_buf()?.starts_with(LOG_HEADER[0].as_bytes());
+
+    Ok(Some(ReaderBuilder::new()
+        .has_headers(has_headers)
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(buf)))
+}
+
+/// Reads every row of the log into memory, comments included, for commands
+/// that rewrite it. Commands that only read the log should stream it with
+/// `log_sessions`.
+fn log_records(log_path: &str) -> Result<Vec<StringRecord>, Box<dyn Error>> {
+    match log_reader(log_path)? {
+        Some(mut reader) => Ok(reader.records().collect::<Result<Vec<_>, _>>()?),
+        None => Ok(Vec::new()),
     }
+}
 
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(tmp_path)?;
+/// Rows whose first field starts with `#` are annotations, not sessions.
+fn is_comment(record: &StringRecord) -> bool {
+    record.get(0).is_some_and(|field| field.starts_with('#'))
+}
 
-    let mut writer = Writer::from_writer(file);
-    let start = Local::now();
+/// Where session `index` (as numbered by `list`, from 1) sits among
+/// `records`, which may include comments.
+fn session_position(records: &[StringRecord], index: usize) -> Result<usize, Box<dyn Error>> {
+    let positions: Vec<_> = (0..records.len()).filter(|&i| !is_comment(&records[i])).collect();
 
-    writer.write_record(&[start.to_rfc3339(), desc.to_string(), tags.join(" ")])?;
-    writer.flush()?;
+    match index.checked_sub(1).and_then(|i| positions.get(i)) {
+        Some(&pos) => Ok(pos),
+        None => Err(format!("No session at index {} (the log has {})", index, positions.len()).into()),
+    }
+}
 
-    println!("Begin deep work!");
-    print_start_time(start);
-    print_description(desc);
+/// Where the last session sits among `records`, if there is one.
+fn last_session_position(records: &[StringRecord]) -> Option<usize> {
+    records.iter().rposition(|r| !is_comment(r))
+}
 
-    Ok(())
+fn log_exists(log_path: &str) -> bool {
+    Path::new(log_path).is_file()
 }
 
-fn datetime_from_last_entry(path: &str) -> StringRecord {
-    let file = OpenOptions::new()
-        .read(true)
-        .open(path)
-        .expect("Failed to read temporary file");
+/// Streams every session in the log, reporting the line of any row that
+/// fails to parse and skipping comments. A missing log has no sessions.
+fn log_sessions(log_path: &str) -> Result<impl Iterator<Item = Result<Session, Box<dyn Error>>>, Box<dyn Error>> {
+    log_sessions_delimited(log_path, b',')
+}
 
-    let mut reader = ReaderBuilder::new().
-            has_headers(false).
-            from_reader(file);
+/// Like `log_sessions`, for a log whose fields are separated by `delimiter`.
+fn log_sessions_delimited(log_path: &str, delimiter: u8) -> Result<impl Iterator<Item = Result<Session, Box<dyn Error>>>, Box<dyn Error>> {
+    let path = log_path.to_string();
+    let records = log_reader_delimited(log_path, delimiter)?.into_iter()
+        .flat_map(|reader| reader.into_records())
+        .filter(|r| !matches!(r, Ok(r) if is_comment(r)));
 
-    let iter = reader.records();
-    return iter.last().unwrap().unwrap();
+    Ok(records.map(move |sess| {
+        let record = sess?;
+        Session::from_record(&record).map_err(|e| {
+            let line = record.position().map_or(0, |p| p.line());
+            format!("Bad session on line {} of {}: {}", line, path, e).into()
+        })
+    }))
 }
 
-fn handle_stop(log_path: &str, tmp_path: &str) -> Result<(), Box<dyn Error>> {
-    let path = Path::new(tmp_path);
+/// Parses YYYY-MM-DD, `today`, `yesterday` or a weekday name, which means
+/// the most recent such day (today included).
+fn parse_date(s: &str) -> Result<NaiveDate, Box<dyn Error>> {
+    let today = Local::now().date_naive();
 
-    if !path.is_file() {
-        println!("No active deep work session");
-        return Ok(());
+    match s.to_lowercase().as_str() {
+        "today" => return Ok(today),
+        "yesterday" => return Ok(today - Duration::days(1)),
+        _ => {}
     }
 
-    let stop = Local::now();
-    let record = datetime_from_last_entry(tmp_path);
+    if let Ok(weekday) = s.parse::<Weekday>() {
+        let back = (7 + today.weekday().num_days_from_monday() - weekday.num_days_from_monday()) % 7;
+        return Ok(today - Duration::days(back.into()));
+    }
 
-    let start = DateTime::parse_from_rfc3339(&record[0])?;
-    let desc  = &record[1];
-    let tags = &record[2];
+    NaiveDate::parse_from_str(s, DAY_FMT).map_err(|_| format!(
+        "Invalid date '{}', expected YYYY-MM-DD, today, yesterday or a weekday like monday", s).into())
+}
 
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(log_path)?;
+/// Parses a time as HH:MM or HH:MM:SS, today or after a date accepted by
+/// `parse_date` (e.g. `yesterday 23:30`), or a full RFC 3339 timestamp.
+/// Refuses times in the future.
+fn parse_time(s: &str) -> Result<DateTime<Local>, Box<dyn Error>> {
+    let time = parse_time_on(s, Local::now().date_naive())?;
 
-    let mut writer = Writer::from_writer(file);
-    let elapsed = stop.signed_duration_since(start);
+    if time > Local::now() {
+        return Err(format!("Time '{}' is in the future", s).into());
+    }
 
-    writer.write_record(&[start.to_rfc3339(),
-        stop.to_rfc3339(),
-        elapsed.num_seconds().to_string(),
-        desc.to_string(),
-        tags.to_string()])?;
-    writer.flush()?;
+    Ok(time)
+}
 
-    println!("Deep work complete!");
-    print_start_time(start);
-    print_stop_time(stop);
-    print_elapsed_time(start, stop);
-    print_description(desc);
-    print_tags(tags);
+/// Like `parse_time`, but a bare HH:MM is taken on `day`, and times in the
+/// future are allowed.
+fn parse_time_on(s: &str, day: NaiveDate) -> Result<DateTime<Local>, Box<dyn Error>> {
+    let invalid = || format!("Invalid time '{}', expected HH:MM, a date then HH:MM, or an RFC 3339 timestamp", s);
 
-    remove_file(tmp_path)?;
+    match DateTime::parse_from_rfc3339(s) {
+        Ok(time) => Ok(time.with_timezone(&Local)),
+        Err(_) => {
+            let (day, clock) = match s.rsplit_once(' ') {
+                Some((day, clock)) => (parse_date(day.trim())?, clock),
+                None => (day, s),
+            };
+            Ok(NaiveTime::parse_from_str(clock, "%H:%M:%S")
+                .or_else(|_| NaiveTime::parse_from_str(clock, "%H:%M"))
+                .ok()
+                .and_then(|t| day.and_time(t).and_local_timezone(Local).earliest())
+                .ok_or_else(invalid)?)
+        }
+    }
+}
 
-    Ok(())
+fn parse_month(s: &str) -> Result<NaiveDate, Box<dyn Error>> {
+    NaiveDate::parse_from_str(&format!("{}-01", s), DAY_FMT)
+        .map_err(|_| format!("Invalid month '{}', expected YYYY-MM", s).into())
 }
 
-fn print_start_time<T: TimeZone>(time: DateTime<T>) where
-    T::Offset: Display
-{
-    println!("Start: {}",
-        Colour::Fixed(TXT_COLOUR).paint(time.format(TIME_FMT).to_string()));
+/// Parses a CSV delimiter: `comma`, `tab`, `semicolon` or any single ASCII
+/// character.
+fn parse_delimiter(s: &str) -> Result<u8, Box<dyn Error>> {
+    match s {
+        "comma" => Ok(b','),
+        "tab" => Ok(b'\t'),
+        "semicolon" => Ok(b';'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(format!("Invalid delimiter '{}', expected comma, tab, semicolon or a single character", s).into()),
+    }
 }
 
-fn print_stop_time<T: TimeZone>(time: DateTime<T>) where
-    T::Offset: Display
-{
-    println!("Stop: {}",
-        Colour::Fixed(TXT_COLOUR).paint(time.format(TIME_FMT).to_string()));
+/// Parses a relative span such as `30m`, `4h` or `3d`.
+fn parse_span(s: &str) -> Result<Duration, Box<dyn Error>> {
+    let invalid = || format!("Invalid span '{}', expected a number followed by m, h or d", s);
+
+    let split = s.char_indices().last().map_or(0, |(i, _)| i);
+    let (count, unit) = s.split_at(split);
+    let count = i64::from(count.parse::<u32>().map_err(|_| invalid())?);
+
+    match unit {
+        "m" => Ok(Duration::minutes(count)),
+        "h" => Ok(Duration::hours(count)),
+        "d" => Ok(Duration::days(count)),
+        _ => Err(invalid().into()),
+    }
+}
+
+/// The daily goal set with `DW_DAILY_GOAL`, or else in the config file.
+fn daily_goal(file_hours: Option<f64>) -> Result<Option<i64>, Box<dyn Error>> {
+    match env::var("DW_DAILY_GOAL") {
+        Ok(goal) => Ok(Some(parse_goal(&goal)?)),
+        Err(_) => match file_hours {
+            Some(hours) if hours >= 0.0 => Ok(Some((hours * 3600.0).round() as i64)),
+            Some(hours) => Err(format!("Invalid daily_goal_hours {} in the config file", hours).into()),
+            None => Ok(None),
+        },
+    }
 }
 
-fn print_elapsed_time<S: TimeZone, T: TimeZone>(start: DateTime<S>, stop: DateTime<T>) {
-    let elapsed = stop.signed_duration_since(start);
-    let hrs = elapsed.num_hours();
-    let min = elapsed.num_minutes() - 60*hrs;
-    let sec = elapsed.num_seconds() - 3600*hrs - 60*min;
-    println!("Time Elapsed: {} hour(s), {} minute(s), {} second(s)",
-        Colour::Fixed(TXT_COLOUR).paint(hrs.to_string()),
-        Colour::Fixed(TXT_COLOUR).paint(min.to_string()),
-        Colour::Fixed(TXT_COLOUR).paint(sec.to_string()));
+/// Parses a time of day given as HH:MM.
+fn parse_clock(s: &str) -> Result<NaiveTime, Box<dyn Error>> {
+    NaiveTime::parse_from_str(s, "%H:%M")
+        .map_err(|_| format!("Invalid time of day '{}', expected HH:MM", s).into())
 }
 
-fn print_description(desc: &str) {
-    if desc.len() > 0 {
-        println!("Description: {}", desc);
+/// Whether `time` is at or after `after` and before `before`. When `after`
+/// is the later of the two the window wraps past midnight, so 22:00 to
+/// 06:00 covers the night.
+fn in_time_window(time: NaiveTime, after: Option<NaiveTime>, before: Option<NaiveTime>) -> bool {
+    match (after, before) {
+        (Some(after), Some(before)) if after > before => time >= after || time < before,
+        (after, before) => after.is_none_or(|a| time >= a) && before.is_none_or(|b| time < b),
     }
 }
 
-fn print_tags(tags: &str) {
-    if tags.len() > 0 {
-        println!("Tags: {}", tags);
+/// Parses a goal given in (possibly fractional) hours into seconds.
+fn parse_goal(s: &str) -> Result<i64, Box<dyn Error>> {
+    match s.parse::<f64>() {
+        Ok(hours) if hours >= 0.0 => Ok((hours * 3600.0).round() as i64),
+        _ => Err(format!("Invalid goal '{}', expected a number of hours", s).into()),
     }
 }
 
-fn handle_status(tmp_path: &str) -> Result<(), Box<dyn Error>> {
-    let path = Path::new(tmp_path);
+fn handle_summary(cfg: &Config, log_path: &str, window: Window, opts: &SummaryOptions) -> Result<(), Box<dyn Error>> {
+    let json = opts.json;
 
-    if !path.is_file() {
-        println!("No active deep work session");
+    if !json && !opts.bare && !log_exists(log_path) && opts.active.is_none() {
+        say!("{}", NOTHING_LOGGED);
+    }
+
+    let mut total_dw_time = 0;
+    let mut session_count = 0;
+    let mut longest = 0;
+    let mut lifetime = 0;
+    let mut breakdown: HashMap<String, i64> = HashMap::new();
+
+    let filter: Vec<&str> = opts.tags.iter().map(|t| canonical_tag(cfg, t)).collect();
+
+    for sess in log_sessions(log_path)?.chain(opts.active.clone().map(Ok)) {
+        let sess = sess?;
+        let duration = sess.elapsed_seconds;
+        lifetime += duration;
+        let tags = canonical_tags(cfg, &sess.tags);
+        let tagged = filter.is_empty() || tags.iter().any(|t| filter.contains(&t.as_str()));
+        let time = match opts.tz {
+            Some(tz) => sess.start.with_timezone(&tz).time(),
+            None => sess.start.time(),
+        };
+
+        if window.contains(sess.start, opts.tz) && tagged && in_time_window(time, opts.after, opts.before) {
+            total_dw_time += duration;
+            session_count += 1;
+            longest = longest.max(duration);
+
+            if opts.by_category {
+                *breakdown.entry(sess.category_label().to_string()).or_insert(0) += duration;
+            } else {
+                for group in tag_groups(&tags, opts.depth) {
+                    *breakdown.entry(group).or_insert(0) += duration;
+                }
+            }
+        }
+    }
+
+    if opts.bare {
+        say!("{}", total_dw_time);
         return Ok(());
     }
 
-    let now = Local::now();
-    let record = datetime_from_last_entry(tmp_path);
-    let start = DateTime::parse_from_rfc3339(&record[0])?;
-    let desc = &record[1];
-    let tags = &record[2];
+    let (hrs, minutes, seconds) = hms(total_dw_time);
 
-    print_start_time(start);
-    print_elapsed_time(start, now);
-    print_description(desc);
-    print_tags(tags);
+    if json {
+        let mut summary = match window {
+            Window::Day(day) => serde_json::json!({ "date": day.format(DAY_FMT).to_string() }),
+            Window::Week(week) => serde_json::json!({ "week": format!("{}-W{:02}", week.year(), week.week()) }),
+            Window::Month(month) => serde_json::json!({ "month": month.format("%Y-%m").to_string() }),
+            Window::Range(from, to) => serde_json::json!({
+                "from": from.format(DAY_FMT).to_string(),
+                "to": to.format(DAY_FMT).to_string(),
+            }),
+            Window::Since(since) => serde_json::json!({
+                "since": since.to_rfc3339_opts(SecondsFormat::Secs, false),
+            }),
+        };
+        summary["total_seconds"] = total_dw_time.into();
+        summary["hours"] = hrs.into();
+        summary["minutes"] = minutes.into();
+        summary["seconds"] = seconds.into();
+        say!("{}", summary);
+        return Ok(());
+    }
+
+    match window {
+        Window::Day(day) => say!("Deep work summary for {}:", day.format(&cfg.date_fmt)),
+        Window::Week(week) => say!("Deep work summary for week {} of {}:",
+            week.week(), week.year()),
+        Window::Month(month) => say!("Deep work summary for {}:", month.format("%B %Y")),
+        Window::Range(from, to) => say!("Deep work summary for {} to {}:",
+            from.format(DAY_FMT), to.format(DAY_FMT)),
+        Window::Since(since) => say!("Deep work summary since {} {}:",
+            since.format(DAY_FMT), since.format(&cfg.time_fmt)),
+    }
+    say!("{}", paint(cfg, format_duration(total_dw_time, cfg.duration_style)));
+
+    if opts.lifetime {
+        let (hrs, minutes, _) = hms(lifetime);
+        say!("All-time: {}", paint(cfg, format!("{}h {}m", hrs, minutes)));
+    }
+
+    if session_count > 0 {
+        let (hrs, minutes, seconds) = hms(longest);
+        say!("{} session(s), longest {}",
+            paint(cfg, session_count.to_string()),
+            paint(cfg, format!("{:02}:{:02}:{:02}", hrs, minutes, seconds)));
+    }
+
+    if opts.avg {
+        let average = if session_count > 0 {
+            let (hrs, minutes, seconds) = hms(total_dw_time / session_count);
+            format!("{:02}:{:02}:{:02}", hrs, minutes, seconds)
+        } else {
+            "n/a".to_string()
+        };
+        say!("Average session: {}", paint(cfg, average));
+    }
+
+    if let Some(goal) = opts.goal {
+        print_progress(cfg, total_dw_time, goal);
+    }
+
+    let mut breakdown: Vec<_> = breakdown.into_iter().collect();
+    breakdown.sort();
+
+    for (group, time) in breakdown {
+        say!("  {}: {}", group, paint(cfg, format_duration(time, cfg.duration_style)));
+    }
 
     Ok(())
 }
+
+/// The tag a report counts `tag` under, going by the `tag_aliases` table
+/// in the config file. Aliases are applied when reading, so the log keeps
+/// the tags as they were typed.
+fn canonical_tag<'a>(cfg: &'a Config, tag: &'a str) -> &'a str {
+    cfg.tag_aliases.get(tag).map_or(tag, String::as_str)
+}
+
+fn canonical_tags(cfg: &Config, tags: &[String]) -> Vec<String> {
+    tags.iter().map(|t| canonical_tag(cfg, t).to_string()).collect()
+}
+
+/// The groups a session with `tags` counts towards: each tag cut down to
+/// `depth` levels, or `UNTAGGED` if it has none. A session counts once per
+/// group, even if several of its tags roll up into the same one.
+fn tag_groups(tags: &[String], depth: Option<usize>) -> Vec<String> {
+    if tags.is_empty() {
+        return vec![UNTAGGED.to_string()];
+    }
+
+    let mut groups: Vec<String> = Vec::new();
+    for tag in tags {
+        let group = match depth {
+            Some(depth) => tag.split('/').take(depth).collect::<Vec<_>>().join("/"),
+            None => tag.clone(),
+        };
+        if !groups.contains(&group) {
+            groups.push(group);
+        }
+    }
+    groups
+}
+
+/// Prints a bar per tag for the sessions in `window`, scaled so the
+/// largest total fills `CHART_WIDTH`.
+fn handle_chart(cfg: &Config, log_path: &str, window: Window) -> Result<(), Box<dyn Error>> {
+    let mut totals: HashMap<String, i64> = HashMap::new();
+
+    for sess in log_sessions(log_path)? {
+        let sess = sess?;
+        if window.contains(sess.start, None) {
+            for group in tag_groups(&canonical_tags(cfg, &sess.tags), None) {
+                *totals.entry(group).or_insert(0) += sess.elapsed_seconds;
+            }
+        }
+    }
+
+    if totals.is_empty() {
+        say!("{}", if log_exists(log_path) { "No deep work to chart yet" } else { NOTHING_LOGGED });
+        return Ok(());
+    }
+
+    let mut totals: Vec<_> = totals.into_iter().collect();
+    totals.sort();
+
+    let max = totals.iter().map(|(_, time)| *time).max().unwrap_or(0).max(1);
+    let name_width = totals.iter().map(|(tag, _)| tag.chars().count()).max().unwrap_or(0);
+
+    for (i, (tag, time)) in totals.iter().enumerate() {
+        // Anything logged at all gets at least one block.
+        let len = ((*time as f64 / max as f64) * CHART_WIDTH as f64).round().max(1.0) as usize;
+        let bar = "█".repeat(len);
+        let bar = if cfg.colored {
+            Colour::Fixed(CHART_COLOURS[i % CHART_COLOURS.len()]).paint(bar).to_string()
+        } else {
+            bar
+        };
+        let (hrs, minutes, _) = hms(*time);
+        say!("{:<width$}  {}{}  {}h{:02}m", tag, bar, " ".repeat(CHART_WIDTH - len),
+            hrs, minutes, width = name_width);
+    }
+
+    Ok(())
+}
+
+/// Lists tags from most to least deep work, within `window` if given and
+/// over the whole log otherwise. Percentages are of the total time logged,
+/// so a session with several tags counts towards each of them.
+fn handle_top(cfg: &Config, log_path: &str, window: Option<Window>, limit: Option<usize>)
+        -> Result<(), Box<dyn Error>> {
+    let mut totals: HashMap<String, i64> = HashMap::new();
+    let mut total = 0;
+
+    for sess in log_sessions(log_path)? {
+        let sess = sess?;
+        if window.as_ref().is_none_or(|w| w.contains(sess.start, None)) {
+            total += sess.elapsed_seconds;
+            for group in tag_groups(&canonical_tags(cfg, &sess.tags), None) {
+                *totals.entry(group).or_insert(0) += sess.elapsed_seconds;
+            }
+        }
+    }
+
+    if totals.is_empty() {
+        say!("{}", if log_exists(log_path) { "No deep work to rank yet" } else { NOTHING_LOGGED });
+        return Ok(());
+    }
+
+    let mut totals: Vec<_> = totals.into_iter().collect();
+    totals.sort_by(|(a, a_time), (b, b_time)| b_time.cmp(a_time).then_with(|| a.cmp(b)));
+    totals.truncate(limit.unwrap_or(totals.len()));
+
+    let name_width = totals.iter().map(|(tag, _)| tag.chars().count()).max().unwrap_or(0);
+    let durations: Vec<_> = totals.iter()
+        .map(|(_, time)| format_duration(*time, cfg.duration_style))
+        .collect();
+    let duration_width = durations.iter().map(String::len).max().unwrap_or(0);
+
+    for (i, ((tag, time), duration)) in totals.iter().zip(durations).enumerate() {
+        let percent = if total > 0 { *time as f64 * 100.0 / total as f64 } else { 0.0 };
+        say!("{:>2}. {:<width$}  {}  {:>5.1}%", i + 1, tag,
+            paint(cfg, format!("{:<width$}", duration, width = duration_width)),
+            percent, width = name_width);
+    }
+
+    Ok(())
+}
+
+/// Reports every row of the log that doesn't parse, stops before it starts,
+/// or has an elapsed time other than the time between its start and stop.
+/// A negative elapsed time can only be damage, and `fix` recomputes it from
+/// start and stop. Longer and shorter ones are listed with their likely
+/// cause, `stop --round` or pauses, and are only recomputed if `all` is
+/// given too, since that throws the rounding or pauses away.
+fn handle_doctor(log_path: &str, fix: bool, all: bool) -> Result<(), Box<dyn Error>> {
+    if !log_exists(log_path) {
+        say!("{}", NOTHING_LOGGED);
+        return Ok(());
+    }
+
+    let mut records = log_records(log_path)?;
+    let mut sessions = 0;
+    let mut comments = 0;
+    let mut problems = 0;
+    let mut longer = 0;
+    let mut shorter = 0;
+    let mut fixed = 0;
+
+    for record in records.iter_mut() {
+        if is_comment(record) {
+            comments += 1;
+            continue;
+        }
+        sessions += 1;
+
+        let line = record.position().map_or(0, |p| p.line());
+        let sess = match Session::from_record(record) {
+            Ok(sess) => sess,
+            Err(e) => {
+                say!("Line {}: {}", line, e);
+                problems += 1;
+                continue;
+            }
+        };
+
+        let wall = sess.stop.signed_duration_since(sess.start).num_seconds();
+        let elapsed = sess.elapsed_seconds;
+        let fixable = if wall < 0 {
+            say!("Line {}: stops before it starts", line);
+            problems += 1;
+            false
+        } else if elapsed < 0 {
+            say!("Line {}: elapsed {}s is negative", line, elapsed);
+            problems += 1;
+            fix
+        } else if elapsed > wall {
+            say!("Line {}: elapsed {}s is longer than the {}s between start and stop (rounded with `stop --round`?)",
+                line, elapsed, wall);
+            longer += 1;
+            fix && all
+        } else if elapsed < wall {
+            say!("Line {}: elapsed {}s is shorter than the {}s between start and stop (paused?)",
+                line, elapsed, wall);
+            shorter += 1;
+            fix && all
+        } else {
+            false
+        };
+
+        if fixable {
+            let sess = Session { elapsed_seconds: wall, ..sess };
+            *record = StringRecord::from(sess.to_record());
+            fixed += 1;
+        }
+    }
+
+    say!("Checked {} session row(s) and {} comment(s) in {}", sessions, comments, log_path);
+    if problems == 0 {
+        say!("No problems found");
+    } else {
+        say!("{} problem(s) found", problems);
+    }
+    if longer > 0 {
+        say!("{} session(s) longer than their start and stop, possibly rounded", longer);
+    }
+    if shorter > 0 {
+        say!("{} session(s) shorter than their start and stop, possibly paused", shorter);
+    }
+
+    if fixed > 0 {
+        rewrite_log(log_path, &records)?;
+        say!("Recomputed {} elapsed time(s) from start and stop", fixed);
+    }
+
+    Ok(())
+}
+
+/// The deep work logged on each day, only counting the days from the first
+/// to the last of `range` if given, in one pass over the log. Only a total per day is kept, so memory grows with
+/// the number of days logged rather than sessions.
+fn daily_totals(log_path: &str, range: Option<(NaiveDate, NaiveDate)>)
+        -> Result<BTreeMap<NaiveDate, i64>, Box<dyn Error>> {
+    let mut totals: BTreeMap<NaiveDate, i64> = BTreeMap::new();
+
+    for sess in log_sessions(log_path)? {
+        let sess = sess?;
+        let day = sess.start.date_naive();
+        if range.is_none_or(|(first, last)| first <= day && day <= last) {
+            *totals.entry(day).or_insert(0) += sess.elapsed_seconds;
+        }
+    }
+
+    Ok(totals)
+}
+
+/// Lifetime totals, in one pass over the log.
+fn handle_stats(cfg: &Config, log_path: &str) -> Result<(), Box<dyn Error>> {
+    if !log_exists(log_path) {
+        say!("{}", NOTHING_LOGGED);
+    }
+
+    let days = daily_totals(log_path, None)?;
+
+    let total: i64 = days.values().sum();
+    let average = if days.is_empty() { 0 } else { total / days.len() as i64 };
+
+    let today = Local::now().date_naive();
+    let (current, longest) = streaks(days.keys().copied(), today);
+
+    say!("Deep work stats:");
+    say!("Total: {} hour(s)",
+        paint(cfg, format!("{:.1}", total as f64 / 3600.0)));
+    say!("Current streak: {} day(s)",
+        paint(cfg, current.to_string()));
+    say!("Longest streak: {} day(s)",
+        paint(cfg, longest.to_string()));
+    say!("Daily average: {} minute(s)",
+        paint(cfg, (average / 60).to_string()));
+
+    if let Some(goal) = cfg.daily_goal {
+        let met = days.iter().filter(|(_, &total)| total >= goal).map(|(&day, _)| day);
+        let (current, longest) = streaks(met, today);
+        say!("Current goal streak: {} day(s)",
+            paint(cfg, current.to_string()));
+        say!("Longest goal streak: {} day(s)",
+            paint(cfg, longest.to_string()));
+    }
+
+    Ok(())
+}
+
+/// The current and longest runs of consecutive days in `days`, which must
+/// be in order. Today isn't over yet, so a run that reached yesterday is
+/// still current.
+fn streaks(days: impl Iterator<Item = NaiveDate>, today: NaiveDate) -> (u32, u32) {
+    let mut longest = 0;
+    let mut streak = 0;
+    let mut prev: Option<NaiveDate> = None;
+
+    for day in days {
+        streak = match prev {
+            Some(p) if p.succ_opt() == Some(day) => streak + 1,
+            _ => 1,
+        };
+        longest = longest.max(streak);
+        prev = Some(day);
+    }
+
+    let current = match prev {
+        Some(last) if last == today || last.succ_opt() == Some(today) => streak,
+        _ => 0,
+    };
+
+    (current, longest)
+}
+
+/// Daily totals for the last `days` days, in one pass over the log.
+fn handle_report(cfg: &Config, log_path: &str, days: u32) -> Result<(), Box<dyn Error>> {
+    let today = Local::now().date_naive();
+    let first = today - Duration::days(i64::from(days.max(1)) - 1);
+
+    let totals = daily_totals(log_path, Some((first, today)))?;
+
+    say!("Deep work report for {} to {}:", first.format(DAY_FMT), today.format(DAY_FMT));
+
+    let mut grand_total = 0;
+    let mut active_days = 0;
+
+    for day in first.iter_days().take_while(|&day| day <= today) {
+        let total = totals.get(&day).copied().unwrap_or(0);
+        grand_total += total;
+        if total > 0 {
+            active_days += 1;
+        }
+
+        let (hrs, min, _) = hms(total);
+        let date = day.format("%a %Y-%m-%d").to_string();
+        let duration = format!("{}h{:02}m", hrs, min);
+
+        if total > 0 {
+            say!("{}  {}", date, paint(cfg, duration));
+        } else {
+            say!("{}", dim(cfg, format!("{}  {}", date, duration)));
+        }
+    }
+
+    let (hrs, min, _) = hms(grand_total);
+    let (avg_hrs, avg_min, _) = hms(if active_days > 0 { grand_total / active_days } else { 0 });
+
+    say!("{}", "-".repeat(22));
+    say!("Total: {} over {} active day(s), {}h{:02}m per active day",
+        paint(cfg, format!("{}h{:02}m", hrs, min)),
+        active_days,
+        avg_hrs, avg_min);
+
+    Ok(())
+}
+
+/// Lists the newest `limit` sessions newest first, or with `oldest_first`
+/// the oldest `limit` sessions oldest first.
+fn handle_list(cfg: &Config, log_path: &str, limit: Option<usize>, width: usize, oldest_first: bool) -> Result<(), Box<dyn Error>> {
+    if !log_exists(log_path) {
+        say!("{}", NOTHING_LOGGED);
+        return Ok(());
+    }
+
+    let mut sessions = VecDeque::new();
+
+    for (index, sess) in log_sessions(log_path)?.enumerate() {
+        if oldest_first && limit == Some(sessions.len()) {
+            break;
+        }
+        sessions.push_back((index + 1, sess?));
+        if let Some(n) = limit {
+            if sessions.len() > n {
+                sessions.pop_front();
+            }
+        }
+    }
+
+    let sessions: Vec<(usize, Session)> = if oldest_first {
+        sessions.into_iter().collect()
+    } else {
+        sessions.into_iter().rev().collect()
+    };
+    let mut rows = Vec::with_capacity(sessions.len());
+
+    for (index, sess) in &sessions {
+        let (hrs, min, _) = hms(sess.elapsed_seconds);
+
+        rows.push([index.to_string(),
+            sess.start.format(DAY_FMT).to_string(),
+            format!("{}-{}", sess.start.format(&cfg.time_fmt), sess.stop.format(&cfg.time_fmt)),
+            format!("{}h{:02}m", hrs, min),
+            sess.tags.join(" "),
+            // Keep multi-line descriptions from breaking up the table.
+            truncate(sess.description.lines().next().unwrap_or(""), width)]);
+    }
+
+    // The description is the last column, so it never needs padding.
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in &rows {
+        let line = format!("{:>w0$}  {:<w1$}  {:<w2$}  {}  {:<w4$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            paint(cfg, format!("{:<w$}", row[3], w = widths[3])),
+            row[4],
+            row[5],
+            w0 = widths[0], w1 = widths[1], w2 = widths[2], w4 = widths[4]);
+        say!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+fn handle_export(log_path: &str, out: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+    let sessions = log_sessions(log_path)?.collect::<Result<Vec<_>, _>>()?;
+
+    writeln!(out, "{}", serde_json::to_string_pretty(&sessions)?)?;
+
+    Ok(())
+}
+
+fn handle_export_csv(log_path: &str, out: &mut dyn io::Write, delimiter: u8) -> Result<(), Box<dyn Error>> {
+    let mut writer = WriterBuilder::new().delimiter(delimiter).from_writer(out);
+    writer.write_record(["start", "stop", "elapsed", "description", "tags", "category"])?;
+
+    for sess in log_sessions(log_path)? {
+        let sess = sess?;
+        let (hrs, min, sec) = hms(sess.elapsed_seconds);
+
+        writer.write_record([sess.start.to_rfc3339(),
+            sess.stop.to_rfc3339(),
+            format!("{:02}:{:02}:{:02}", hrs, min, sec),
+            sess.description.clone(),
+            sess.tags.join(","),
+            sess.category.clone().unwrap_or_default()])?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn handle_export_ical(log_path: &str, out: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+    let ical_time = |time: DateTime<FixedOffset>| time.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ");
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//deep-work//dw//EN".to_string(),
+    ];
+
+    for sess in log_sessions(log_path)? {
+        let sess = sess?;
+        let summary = if sess.description.is_empty() { "Deep work" } else { &sess.description };
+
+        lines.push("BEGIN:VEVENT".to_string());
+        // Named sessions can start in the same second, so the UID is built
+        // from both ends, to the nanosecond.
+        lines.push(format!("UID:{}.{:09}-{}.{:09}@deep-work",
+            sess.start.timestamp(), sess.start.timestamp_subsec_nanos(),
+            sess.stop.timestamp(), sess.stop.timestamp_subsec_nanos()));
+        lines.push(format!("DTSTAMP:{}", ical_time(sess.stop)));
+        lines.push(format!("DTSTART:{}", ical_time(sess.start)));
+        lines.push(format!("DTEND:{}", ical_time(sess.stop)));
+        lines.push(format!("SUMMARY:{}", ical_escape(summary)));
+
+        let categories: Vec<_> = sess.category.iter()
+            .chain(sess.tags.iter())
+            .map(|c| ical_escape(c))
+            .collect();
+        if !categories.is_empty() {
+            lines.push(format!("CATEGORIES:{}", categories.join(",")));
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+
+    lines.push("END:VCALENDAR".to_string());
+
+    for line in lines {
+        writeln!(out, "{}\r", ical_fold(&line))?;
+    }
+
+    Ok(())
+}
+
+/// Escapes the characters iCalendar treats specially in text values.
+fn ical_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Folds a content line so no line is longer than the 75 octets iCalendar
+/// allows, continuing on lines that start with a space.
+fn ical_fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut width = 0;
+
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+
+    folded
+}
+
+/// Tags are stored space separated, so a tag containing whitespace would
+/// silently turn into several tags.
+fn validate_tag(tag: &str) -> Result<(), Box<dyn Error>> {
+    if tag.contains(|c: char| c.is_whitespace() || c == ',') {
+        return Err(format!("Tag '{}' is invalid: tags cannot contain spaces or commas \
+            (try '-' or '_' instead)", tag).into());
+    }
+
+    Ok(())
+}
+
+/// `tags` followed by any of `project` not already among them.
+fn with_project_tags<'a>(tags: Vec<&'a str>, project: &'a [String]) -> Vec<&'a str> {
+    let mut merged: Vec<&str> = Vec::new();
+    for tag in tags.into_iter().chain(project.iter().map(String::as_str)) {
+        if !tag.is_empty() && !merged.contains(&tag) {
+            merged.push(tag);
+        }
+    }
+    merged
+}
+
+/// Default tags for the project in the current directory, one per line of
+/// its `.dwtags` file.
+fn project_tags() -> Result<Vec<String>, Box<dyn Error>> {
+    verbose("reading project tags from", DW_TAGS);
+    match read_to_string(DW_TAGS) {
+        Ok(contents) => Ok(contents.lines()
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(String::from)
+            .collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `dw start`: takes the lock on the directory holding `tmp_path`, unless
+/// it's a dry run, then resumes a recent session or starts a new one.
+/// Returns whether either happened.
+fn start_session(cfg: &Config, log_path: &str, tmp_path: &str, desc: &str, tags: Vec<&str>, opts: &StartOptions, dry_run: bool) -> Result<bool, Box<dyn Error>> {
+    let _lock = match Path::new(tmp_path).parent() {
+        Some(dir) if !dry_run => Some(lock(dir, lock_retries()?)?),
+        _ => None,
+    };
+
+    if let Some(minutes) = opts.resume_within {
+        let project = project_tags()?;
+        let wanted = with_project_tags(tags.clone(), &project);
+        if resume_if_recent(cfg, log_path, tmp_path, &wanted, minutes, opts.at, dry_run)? {
+            return Ok(true);
+        }
+    }
+
+    handle_start(cfg, tmp_path, desc, tags, opts, dry_run)
+}
+
+/// Starts a session unless one is already active. Returns whether it did.
+fn handle_start(cfg: &Config, tmp_path: &str, desc: &str, tags: Vec<&str>, opts: &StartOptions, dry_run: bool) -> Result<bool, Box<dyn Error>> {
+    let start = opts.at;
+    let project_tags = project_tags()?;
+    let tags = with_project_tags(tags, &project_tags);
+
+    for tag in &tags {
+        validate_tag(tag)?;
+    }
+
+    let path = Path::new(tmp_path);
+
+    if path.is_file() {
+        say!("Another deep work session is active");
+        return Ok(false);
+    }
+
+    let category = opts.category;
+    let target = opts.pomodoro.map(|minutes| start + Duration::minutes(minutes.into()));
+
+    if dry_run {
+        say!("Dry run, not starting a session");
+    } else {
+        verbose("creating tmp file", tmp_path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(tmp_path)?;
+
+        // The writer quotes fields as needed, so a description keeps any
+        // commas, quotes or newlines through to the log. Tags are joined
+        // with spaces, which is why validate_tag refuses spaces and commas.
+        let mut writer = Writer::from_writer(file);
+        writer.write_record(&[start.to_rfc3339(),
+            desc.to_string(),
+            tags.join(" "),
+            category.to_string(),
+            target.map(|t| t.to_rfc3339()).unwrap_or_default()])?;
+        writer.flush()?;
+    }
+
+    say!("Begin deep work!");
+    print_start_time(cfg, start);
+    print_description(cfg, desc);
+    print_category(category);
+    if let Some(hour) = cfg.warn_after_hour {
+        if Local::now().hour() >= hour {
+            say!("{}", warn(cfg, format!("It's after {:02}:00 — consider resting", hour)));
+        }
+    }
+    if let Some(target) = target {
+        say!("Pomodoro ends: {}",
+            paint(cfg, target.format(&cfg.time_fmt).to_string()));
+    }
+
+    Ok(true)
+}
+
+/// The last session in the log, read without holding the others in memory.
+fn last_session(log_path: &str) -> Result<Option<Session>, Box<dyn Error>> {
+    let mut last = None;
+    for sess in log_sessions(log_path)? {
+        last = Some(sess?);
+    }
+    Ok(last)
+}
+
+fn handle_last(cfg: &Config, log_path: &str) -> Result<(), Box<dyn Error>> {
+    let last = match last_session(log_path)? {
+        Some(last) => last,
+        None => {
+            say!("No completed sessions");
+            return Ok(());
+        }
+    };
+
+    print_start_time(cfg, last.start);
+    print_stop_time(cfg, last.stop);
+    print_elapsed_time(cfg, Duration::seconds(last.elapsed_seconds));
+    print_description(cfg, &last.description);
+    print_tags(&last.tags.join(" "));
+    print_category(last.category.as_deref().unwrap_or(""));
+
+    Ok(())
+}
+
+fn handle_resume_last(cfg: &Config, log_path: &str, tmp_path: &str, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let last = match last_session(log_path)? {
+        Some(last) => last,
+        None => {
+            say!("No completed sessions to resume");
+            return Ok(());
+        }
+    };
+
+    let tags = last.tags.iter().map(String::as_str).collect();
+    let opts = StartOptions {
+        category: last.category.as_deref().unwrap_or(""),
+        pomodoro: None,
+        at: Local::now(),
+        resume_within: None,
+    };
+    handle_start(cfg, tmp_path, &last.description, tags, &opts, dry_run)?;
+
+    Ok(())
+}
+
+/// The session row of the active session file at `path`, skipping any
+/// markers. Returns `None` if there is no session row, e.g. because a crash
+/// left the file empty.
+fn datetime_from_last_entry(path: &str) -> Result<Option<StringRecord>, Box<dyn Error>> {
+    verbose("reading tmp file", path);
+    let file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .map_err(|e| format!("Failed to read active session file {}: {}", path, e))?;
+
+    let mut reader = ReaderBuilder::new().
+            has_headers(false).
+            flexible(true).
+            from_reader(file);
+
+    let iter = reader.records()
+        .filter(|r| !matches!(r, Ok(r) if is_marker(r)));
+    match iter.last() {
+        Some(record) => {
+            let record = record?;
+            // Callers index the start, description and tags directly.
+            if record.len() < 3 {
+                return Err(CORRUPT_TMP.into());
+            }
+            Ok(Some(record))
+        }
+        None => Ok(None),
+    }
+}
+
+fn is_marker(record: &StringRecord) -> bool {
+    &record[0] == PAUSE || &record[0] == RESUME || &record[0] == NOTE
+}
+
+/// A note added with `dw note`, and when it was added.
+type Note = (DateTime<FixedOffset>, String);
+
+/// The notes added to the active session, oldest first.
+fn session_notes(path: &str) -> Result<Vec<Note>, Box<dyn Error>> {
+    verbose("reading tmp file", path);
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+
+    let mut notes = Vec::new();
+    for rec in reader.records() {
+        let record = rec?;
+        if &record[0] == NOTE {
+            if record.len() < 3 {
+                return Err(CORRUPT_TMP.into());
+            }
+            notes.push((DateTime::parse_from_rfc3339(&record[1])?, record[2].to_string()));
+        }
+    }
+
+    Ok(notes)
+}
+
+/// The active session at `tmp_path` as it would be logged if it stopped
+/// now, or `None` if there isn't one.
+fn active_session(tmp_path: &str) -> Result<Option<Session>, Box<dyn Error>> {
+    if !Path::new(tmp_path).is_file() {
+        return Ok(None);
+    }
+
+    let record = match datetime_from_last_entry(tmp_path)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    let start = DateTime::parse_from_rfc3339(&record[0]).map_err(|_| CORRUPT_TMP)?;
+    let now = Local::now();
+    let (paused, _) = paused_time(tmp_path, now)?;
+
+    Ok(Some(Session {
+        start,
+        stop: now.fixed_offset(),
+        elapsed_seconds: (now.signed_duration_since(start) - paused).num_seconds(),
+        description: record[1].to_string(),
+        tags: record[2].split_whitespace().map(String::from).collect(),
+        category: record.get(3).filter(|c| !c.is_empty()).map(String::from),
+    }))
+}
+
+/// Total time spent paused in the active session up to `now`, and whether
+/// the session is paused right now.
+fn paused_time<T: TimeZone>(path: &str, now: DateTime<T>) -> Result<(Duration, bool), Box<dyn Error>> {
+    verbose("reading tmp file", path);
+    let file = OpenOptions::new()
+        .read(true)
+        .open(path)?;
+
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(file);
+
+    let mut paused = Duration::zero();
+    let mut paused_at = None;
+
+    for rec in reader.records() {
+        let record = rec?;
+        if &record[0] == PAUSE && paused_at.is_none() {
+            paused_at = Some(DateTime::parse_from_rfc3339(&record[1])?);
+        } else if &record[0] == RESUME {
+            if let Some(at) = paused_at.take() {
+                let resumed = DateTime::parse_from_rfc3339(&record[1])?;
+                paused += resumed.signed_duration_since(at);
+            }
+        }
+    }
+
+    if let Some(at) = paused_at {
+        paused += now.signed_duration_since(at);
+    }
+
+    Ok((paused, paused_at.is_some()))
+}
+
+fn append_marker(path: &str, marker: &str, time: DateTime<Local>) -> Result<(), Box<dyn Error>> {
+    append_tmp_row(path, &[marker, &time.to_rfc3339()])
+}
+
+fn append_tmp_row(path: &str, row: &[&str]) -> Result<(), Box<dyn Error>> {
+    verbose(&format!("appending {} marker to", row[0]), path);
+    let file = OpenOptions::new()
+        .append(true)
+        .open(path)?;
+
+    let mut writer = Writer::from_writer(file);
+    writer.write_record(row)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn handle_note(cfg: &Config, tmp_path: &str, text: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(tmp_path).is_file() {
+        say!("No active deep work session");
+        return Ok(());
+    }
+
+    check_tmp(tmp_path)?;
+
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("Note is empty".into());
+    }
+
+    let now = Local::now();
+    append_tmp_row(tmp_path, &[NOTE, &now.to_rfc3339(), text])?;
+
+    say!("Note added at {}: {}",
+        paint(cfg, now.format(&cfg.time_fmt).to_string()), text);
+
+    Ok(())
+}
+
+/// Fails if the active session file at `tmp_path` has no session row, or
+/// one whose start can't be read, so nothing is added to a file that
+/// `stop` would refuse anyway.
+fn check_tmp(tmp_path: &str) -> Result<(), Box<dyn Error>> {
+    let record = datetime_from_last_entry(tmp_path)?.ok_or(EMPTY_TMP)?;
+    DateTime::parse_from_rfc3339(&record[0]).map_err(|_| CORRUPT_TMP)?;
+    Ok(())
+}
+
+fn handle_pause(cfg: &Config, tmp_path: &str) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(tmp_path);
+
+    if !path.is_file() {
+        say!("No active deep work session");
+        return Ok(());
+    }
+
+    check_tmp(tmp_path)?;
+
+    let now = Local::now();
+    let (_, paused) = paused_time(tmp_path, now)?;
+
+    if paused {
+        say!("Deep work session is already paused");
+        return Ok(());
+    }
+
+    append_marker(tmp_path, PAUSE, now)?;
+
+    say!("Deep work paused");
+    say!("Paused: {}",
+        paint(cfg, now.format(&cfg.time_fmt).to_string()));
+
+    Ok(())
+}
+
+fn handle_resume(cfg: &Config, tmp_path: &str) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(tmp_path);
+
+    if !path.is_file() {
+        say!("No active deep work session");
+        return Ok(());
+    }
+
+    check_tmp(tmp_path)?;
+
+    let now = Local::now();
+    let (_, paused) = paused_time(tmp_path, now)?;
+
+    if !paused {
+        say!("Deep work session is not paused");
+        return Ok(());
+    }
+
+    append_marker(tmp_path, RESUME, now)?;
+
+    say!("Resume deep work!");
+    say!("Resumed: {}",
+        paint(cfg, now.format(&cfg.time_fmt).to_string()));
+
+    Ok(())
+}
+
+/// Returns whether a session was active.
+fn handle_stop(cfg: &Config, log_path: &str, tmp_path: &str, opts: &StopOptions, stop: DateTime<Local>, dry_run: bool) -> Result<bool, Box<dyn Error>> {
+    let path = Path::new(tmp_path);
+
+    if !path.is_file() {
+        say!("No active deep work session");
+        return Ok(false);
+    }
+
+    // These are errors even with --quiet, or a stop run from cron would
+    // silently never log the session.
+    let record = datetime_from_last_entry(tmp_path)?.ok_or(EMPTY_TMP)?;
+    let start = DateTime::parse_from_rfc3339(&record[0]).map_err(|_| CORRUPT_TMP)?;
+    if stop <= start {
+        return Err(format!("Stop time {} is not after the session started at {}",
+            stop.to_rfc3339(), start.to_rfc3339()).into());
+    }
+
+    let mut desc = record[1].to_string();
+    let tags = &record[2];
+    let category = record.get(3).unwrap_or("");
+
+    let (paused, _) = paused_time(tmp_path, stop)?;
+    // Both ends carry their UTC offset, so this is the physical time between
+    // them even if a daylight saving change happened in between.
+    let elapsed = stop.signed_duration_since(start) - paused;
+
+    if elapsed.num_seconds() < opts.min_duration {
+        if !dry_run {
+            remove_tmp(tmp_path, opts.keep_tmp)?;
+        }
+        say!("Session discarded (under {}s)", opts.min_duration);
+        return Ok(true);
+    }
+
+    if opts.prompt && desc.is_empty() && io::stdin().is_terminal() {
+        desc = prompt_description()?;
+    }
+
+    // The log has no room for notes of their own, so they go on the end of
+    // the description, each with the time it was written.
+    for (at, note) in session_notes(tmp_path)? {
+        if !desc.is_empty() {
+            desc.push_str("; ");
+        }
+        desc.push_str(&format!("{} {}", at.format("%H:%M"), note));
+    }
+
+    let mut elapsed_seconds = elapsed.num_seconds();
+    if let Some(minutes) = opts.round {
+        let step = i64::from(minutes) * 60;
+        elapsed_seconds = (elapsed_seconds + step - 1) / step * step;
+    }
+
+    let sess = Session {
+        start,
+        stop: stop.into(),
+        elapsed_seconds,
+        description: desc.clone(),
+        tags: tags.split_whitespace().map(String::from).collect(),
+        category: Some(category.to_string()).filter(|c| !c.is_empty()),
+    };
+
+    let overlapping = overlapping_sessions(log_path, &sess)?;
+
+    if dry_run {
+        say!("Dry run, not logging:");
+        let mut writer = Writer::from_writer(stdout());
+        writer.write_record(sess.to_record())?;
+        writer.flush()?;
+    } else {
+        append_to_log(log_path, &sess)?;
+    }
+
+    say!("Deep work complete!");
+    print_start_time(cfg, start);
+    print_stop_time(cfg, stop);
+    print_elapsed_time(cfg, elapsed);
+    if opts.round.is_some() {
+        say!("Logged As: {}", paint(cfg, format_duration(elapsed_seconds, cfg.duration_style)));
+    }
+    print_description(cfg, &desc);
+    print_tags(tags);
+    print_category(category);
+
+    for other in overlapping {
+        say!("Warning: overlaps the session from {} to {}{}",
+            paint(cfg, other.start.format(&cfg.time_fmt).to_string()),
+            paint(cfg, other.stop.format(&cfg.time_fmt).to_string()),
+            if other.description.is_empty() { String::new() } else { format!(" ({})", other.description) });
+    }
+
+    if let Some(goal) = cfg.daily_goal {
+        // A dry run didn't log the session, so count it here instead.
+        let mut total = today_total(log_path)?;
+        if dry_run {
+            total += sess.elapsed_seconds;
+        }
+        print_goal(cfg, total, goal);
+    }
+
+    if dry_run {
+        return Ok(true);
+    }
+
+    remove_tmp(tmp_path, opts.keep_tmp)?;
+
+    if opts.notify {
+        notify_stop(elapsed, &desc);
+    }
+
+    Ok(true)
+}
+
+/// Logged sessions whose time overlaps `sess`, as happens when several
+/// named sessions run at once. Rows that fail to parse are skipped, since
+/// this only feeds a warning.
+fn overlapping_sessions(log_path: &str, sess: &Session) -> Result<Vec<Session>, Box<dyn Error>> {
+    Ok(log_sessions(log_path)?
+        .flatten()
+        .filter(|other| other.start < sess.stop && sess.start < other.stop)
+        .collect())
+}
+
+/// Time logged today. Rows that fail to parse are skipped, since this only
+/// feeds the goal line printed by `stop`.
+fn today_total(log_path: &str) -> Result<i64, Box<dyn Error>> {
+    let today = Window::Day(Local::now().date_naive());
+    Ok(log_sessions(log_path)?
+        .flatten()
+        .filter(|sess| today.contains(sess.start, None))
+        .map(|sess| sess.elapsed_seconds)
+        .sum())
+}
+
+/// Reads a one line description from the terminal.
+fn prompt_description() -> Result<String, Box<dyn Error>> {
+    print!("Description: ");
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+
+    Ok(line.trim().to_string())
+}
+
+fn append_to_log(log_path: &str, sess: &Session) -> Result<(), Box<dyn Error>> {
+    append_row_to_log(log_path, &sess.to_record())
+}
+
+fn append_row_to_log(log_path: &str, row: &[String]) -> Result<(), Box<dyn Error>> {
+    verbose("appending to log", log_path);
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+
+    let is_new = file.metadata()?.len() == 0;
+    // Comments are a single field, unlike the header and sessions.
+    let mut writer = WriterBuilder::new().flexible(true).from_writer(file);
+
+    if is_new {
+        writer.write_record(LOG_HEADER)?;
+    }
+
+    writer.write_record(row)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn handle_annotate(log_path: &str, text: &str) -> Result<(), Box<dyn Error>> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("Comment is empty".into());
+    }
+
+    append_row_to_log(log_path, &[format!("# {}", text)])?;
+
+    say!("Comment added: {}", text);
+
+    Ok(())
+}
+
+/// Removes the active session file once `stop` is done with it, unless
+/// `--keep-tmp` asked for it to be left for inspection.
+fn remove_tmp(tmp_path: &str, keep: bool) -> Result<(), Box<dyn Error>> {
+    if keep {
+        say!("Kept active session file {}", tmp_path);
+    } else {
+        verbose("removing tmp file", tmp_path);
+        remove_file(tmp_path)?;
+    }
+
+    Ok(())
+}
+
+/// Sends a desktop notification for a completed session. The session is
+/// already logged by this point, so failures are only reported.
+fn notify_stop(elapsed: Duration, desc: &str) {
+    let mut body = format_duration(elapsed.num_seconds(), DurationStyle::Words);
+    if !desc.is_empty() {
+        body.push('\n');
+        body.push_str(desc);
+    }
+
+    if let Err(e) = Notification::new()
+        .summary("Deep work complete")
+        .body(&body)
+        .show()
+    {
+        eprintln!("Failed to show notification: {}", e);
+    }
+}
+
+fn handle_undo(cfg: &Config, log_path: &str, tmp_path: &str) -> Result<(), Box<dyn Error>> {
+    if Path::new(tmp_path).is_file() {
+        say!("Another deep work session is active");
+        return Ok(());
+    }
+
+    let mut records = log_records(log_path)?;
+
+    let sess = match last_session_position(&records) {
+        Some(pos) => Session::from_record(&records.remove(pos))?,
+        None => {
+            say!("No completed sessions to undo");
+            return Ok(());
+        }
+    };
+
+    reopen_session(log_path, tmp_path, &records, &sess, None)?;
+
+    let tags = sess.tags.join(" ");
+
+    say!("Session reopened!");
+    print_start_time(cfg, sess.start);
+    print_description(cfg, &sess.description);
+    print_tags(&tags);
+    print_category(sess.category.as_deref().unwrap_or(""));
+
+    Ok(())
+}
+
+/// Makes `sess` the active session again and rewrites the log as
+/// `records`, which should no longer contain it. With `resumed_at` the time
+/// from the session's stop until then is recorded as a pause.
+fn reopen_session(log_path: &str, tmp_path: &str, records: &[StringRecord], sess: &Session, resumed_at: Option<DateTime<Local>>) -> Result<(), Box<dyn Error>> {
+    verbose("creating tmp file", tmp_path);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(tmp_path)?;
+
+    let mut writer = Writer::from_writer(file);
+    writer.write_record(&[sess.start.to_rfc3339(),
+        sess.description.clone(),
+        sess.tags.join(" "),
+        sess.category.clone().unwrap_or_default()])?;
+    writer.flush()?;
+
+    // Only the total time paused survives in the log, so carry it over as a
+    // single pause at the start of the session.
+    // The log keeps whole seconds, so compare whole seconds too.
+    let paused = Duration::seconds(sess.stop.signed_duration_since(sess.start).num_seconds()
+        - sess.elapsed_seconds);
+    if paused > Duration::zero() {
+        let start = sess.start.with_timezone(&Local);
+        append_marker(tmp_path, PAUSE, start)?;
+        append_marker(tmp_path, RESUME, start + paused)?;
+    }
+    if let Some(resumed_at) = resumed_at {
+        append_marker(tmp_path, PAUSE, sess.stop.with_timezone(&Local))?;
+        append_marker(tmp_path, RESUME, resumed_at)?;
+    }
+
+    if let Err(e) = rewrite_log(log_path, records) {
+        verbose("removing tmp file", tmp_path);
+        remove_file(tmp_path)?;
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Reopens the last session instead of starting a new one, if it stopped
+/// at most `minutes` before `start` and had the same tags. The time in
+/// between counts as a pause. Returns whether it did.
+fn resume_if_recent(cfg: &Config, log_path: &str, tmp_path: &str, tags: &[&str], minutes: u32, start: DateTime<Local>, dry_run: bool) -> Result<bool, Box<dyn Error>> {
+    if Path::new(tmp_path).is_file() {
+        return Ok(false);
+    }
+
+    let mut records = log_records(log_path)?;
+    let pos = match last_session_position(&records) {
+        Some(pos) => pos,
+        None => return Ok(false),
+    };
+    let last = Session::from_record(&records[pos])?;
+
+    // Tags are compared as the reports count them, so `js` matches a session
+    // tagged `javascript` if one is an alias of the other.
+    let gap = start.signed_duration_since(last.stop);
+    let mut wanted: Vec<&str> = tags.iter().map(|t| canonical_tag(cfg, t)).collect();
+    let mut had: Vec<&str> = last.tags.iter().map(|t| canonical_tag(cfg, t)).collect();
+    for tags in [&mut wanted, &mut had] {
+        tags.sort_unstable();
+        tags.dedup();
+    }
+
+    if gap < Duration::zero() || gap > Duration::minutes(minutes.into()) || wanted != had {
+        return Ok(false);
+    }
+
+    if dry_run {
+        say!("Dry run, not resuming the session that stopped at {}",
+            paint(cfg, last.stop.format(&cfg.time_fmt).to_string()));
+        return Ok(true);
+    }
+
+    records.remove(pos);
+    reopen_session(log_path, tmp_path, &records, &last, Some(start))?;
+
+    say!("Resuming the session that stopped at {}",
+        paint(cfg, last.stop.format(&cfg.time_fmt).to_string()));
+    print_start_time(cfg, last.start);
+    print_description(cfg, &last.description);
+    print_tags(&last.tags.join(" "));
+    print_category(last.category.as_deref().unwrap_or(""));
+
+    Ok(true)
+}
+
+/// Stops the active session if it was started with `--pomodoro` and has now
+/// run for its full length, and otherwise does nothing.
+fn handle_check(cfg: &Config, log_path: &str, tmp_path: &str, notify: bool, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    if !Path::new(tmp_path).is_file() {
+        return Ok(());
+    }
+
+    let record = datetime_from_last_entry(tmp_path)?.ok_or(EMPTY_TMP)?;
+    let target = match record.get(4) {
+        Some(target) if !target.is_empty() => target,
+        _ => return Ok(()),
+    };
+
+    let start = DateTime::parse_from_rfc3339(&record[0]).map_err(|_| CORRUPT_TMP)?;
+    let target = DateTime::parse_from_rfc3339(target).map_err(|_| CORRUPT_TMP)?;
+
+    // Pauses push the end back, so compare time worked rather than the clock.
+    let now = Local::now();
+    let (paused, is_paused) = paused_time(tmp_path, now)?;
+    let elapsed = now.signed_duration_since(start) - paused;
+    let length = target.signed_duration_since(start);
+
+    if elapsed < length {
+        return Ok(());
+    }
+
+    // Log exactly the pomodoro's length, however late this runs. A paused
+    // session stopped counting when it was paused, so stop it now instead.
+    let stop = if is_paused { now } else { now - (elapsed - length) };
+
+    let opts = StopOptions {
+        min_duration: 0,
+        round: None,
+        notify,
+        prompt: false,
+        keep_tmp: false,
+    };
+    handle_stop(cfg, log_path, tmp_path, &opts, stop, dry_run)?;
+
+    Ok(())
+}
+
+fn handle_cancel(tmp_path: &str) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(tmp_path);
+
+    if !path.is_file() {
+        say!("No active deep work session");
+        return Ok(());
+    }
+
+    verbose("removing tmp file", tmp_path);
+    remove_file(tmp_path)?;
+
+    say!("Session cancelled — nothing logged");
+
+    Ok(())
+}
+
+fn handle_edit(cfg: &Config, log_path: &str, desc: Option<&str>, tags: Option<Vec<&str>>) -> Result<(), Box<dyn Error>> {
+    for tag in tags.iter().flatten() {
+        validate_tag(tag)?;
+    }
+    let tags = tags.map(|tags| tags.join(" "));
+
+    let mut records = log_records(log_path)?;
+
+    let last = match last_session_position(&records) {
+        Some(pos) => &mut records[pos],
+        None => {
+            say!("No sessions to edit");
+            return Ok(());
+        }
+    };
+
+    // A short row has no field to put the change in, so it would come out
+    // of the loop below unchanged.
+    Session::from_record(last).map_err(|e| {
+        let line = last.position().map_or(0, |p| p.line());
+        format!("Bad session on line {} of {}: {}; run `dw doctor`", line, log_path, e)
+    })?;
+
+    let edited: StringRecord = last.iter()
+        .enumerate()
+        .map(|(i, field)| match (i, desc, &tags) {
+            (3, Some(desc), _) => desc,
+            (4, _, Some(tags)) => tags,
+            _ => field,
+        })
+        .collect();
+    *last = edited;
+
+    rewrite_log(log_path, &records)?;
+
+    say!("Session updated!");
+    if let Some(desc) = desc {
+        print_description(cfg, desc);
+    }
+    if let Some(tags) = &tags {
+        print_tags(tags);
+    }
+
+    Ok(())
+}
+
+fn handle_delete(cfg: &Config, log_path: &str, index: usize) -> Result<(), Box<dyn Error>> {
+    let mut records = log_records(log_path)?;
+    let pos = session_position(&records, index)?;
+
+    let record = records.remove(pos);
+    rewrite_log(log_path, &records)?;
+
+    // The row may be one `doctor` reported as broken, so show whatever of
+    // it is there rather than failing after it's already gone.
+    say!("Session deleted!");
+    match record.get(0).map(DateTime::parse_from_rfc3339) {
+        Some(Ok(start)) => print_start_time(cfg, start),
+        _ => say!("Row: {}", record.iter().collect::<Vec<_>>().join(",")),
+    }
+    print_description(cfg, record.get(3).unwrap_or(""));
+    print_tags(record.get(4).unwrap_or(""));
+
+    Ok(())
+}
+
+/// Adds the sessions from another log to this one, skipping any that are
+/// already present, and keeps the result in start order.
+fn handle_import(log_path: &str, import_path: &str, delimiter: u8) -> Result<(), Box<dyn Error>> {
+    if !log_exists(import_path) {
+        return Err(format!("No log to import at {}", import_path).into());
+    }
+
+    // Each session is kept together with the comments that follow it, so
+    // sorting moves them along with it. Comments before the first session
+    // have no start and stay at the top.
+    let mut groups: Vec<(Option<DateTime<FixedOffset>>, Vec<StringRecord>)> = Vec::new();
+    let mut seen = HashSet::new();
+
+    for record in log_records(log_path)? {
+        if is_comment(&record) {
+            match groups.last_mut() {
+                Some((_, rows)) => rows.push(record),
+                None => groups.push((None, vec![record])),
+            }
+        } else {
+            let sess = Session::from_record(&record)?;
+            seen.insert((sess.start, sess.stop));
+            groups.push((Some(sess.start), vec![record]));
+        }
+    }
+
+    let mut imported = 0;
+    let mut duplicates = 0;
+
+    for sess in log_sessions_delimited(import_path, delimiter)? {
+        let sess = sess?;
+        if seen.insert((sess.start, sess.stop)) {
+            groups.push((Some(sess.start), vec![StringRecord::from(sess.to_record())]));
+            imported += 1;
+        } else {
+            duplicates += 1;
+        }
+    }
+
+    if imported > 0 {
+        groups.sort_by_key(|(start, _)| *start);
+        let records: Vec<_> = groups.into_iter().flat_map(|(_, rows)| rows).collect();
+        rewrite_log(log_path, &records)?;
+    }
+
+    say!("Imported {} session(s), skipped {} duplicate(s)", imported, duplicates);
+
+    Ok(())
+}
+
+/// Splits session `index` at `at` into two sessions. The first keeps the
+/// original description and tags. The second gets `desc` and `tags`, or
+/// the original's where they are not given.
+fn handle_split(cfg: &Config, log_path: &str, index: usize, at: &str, desc: Option<&str>, tags: Option<Vec<&str>>) -> Result<(), Box<dyn Error>> {
+    for tag in tags.iter().flatten() {
+        validate_tag(tag)?;
+    }
+
+    let mut records = log_records(log_path)?;
+    let pos = session_position(&records, index)?;
+
+    let first = Session::from_record(&records[pos])?;
+
+    // A bare time belongs to the day the session started, or to the next
+    // day for sessions that ran past midnight.
+    let mut split = parse_time_on(at, first.start.date_naive())?.fixed_offset();
+    if split <= first.start && split + Duration::days(1) < first.stop {
+        split += Duration::days(1);
+    }
+    if split <= first.start || split >= first.stop {
+        return Err(format!("{} is not inside the session, which ran from {} to {}",
+            at, first.start.to_rfc3339(), first.stop.to_rfc3339()).into());
+    }
+
+    // Where any pauses fell isn't logged, so share the worked time out in
+    // proportion to each part's length.
+    let total = first.elapsed_seconds;
+    let wall = first.stop.signed_duration_since(first.start).num_milliseconds();
+    let before = split.signed_duration_since(first.start).num_milliseconds();
+    let first_seconds = (i128::from(total) * i128::from(before) / i128::from(wall)) as i64;
+
+    let second = Session {
+        start: split,
+        stop: first.stop,
+        elapsed_seconds: total - first_seconds,
+        description: desc.map(String::from).unwrap_or_else(|| first.description.clone()),
+        tags: match tags {
+            Some(tags) => tags.into_iter().map(String::from).collect(),
+            None => first.tags.clone(),
+        },
+        category: first.category.clone(),
+    };
+    let first = Session {
+        stop: split,
+        elapsed_seconds: first_seconds,
+        ..first
+    };
+
+    records.splice(pos..pos + 1,
+        [StringRecord::from(first.to_record()), StringRecord::from(second.to_record())]);
+    rewrite_log(log_path, &records)?;
+
+    say!("Session split!");
+    for (part, sess) in [&first, &second].iter().enumerate() {
+        let (hrs, min, _) = hms(sess.elapsed_seconds);
+        say!("{}  {}-{}  {}  {}",
+            index + part,
+            sess.start.format(&cfg.time_fmt),
+            sess.stop.format(&cfg.time_fmt),
+            paint(cfg, format!("{}h{:02}m", hrs, min)),
+            sess.description);
+    }
+
+    Ok(())
+}
+
+/// Merges sessions `first` and `first + 1` into one spanning both. It keeps
+/// the first's description and tags. With `include_gap` the time between
+/// them counts as worked; otherwise the two elapsed values are summed.
+fn handle_merge(cfg: &Config, log_path: &str, first: usize, include_gap: bool) -> Result<(), Box<dyn Error>> {
+    let mut records = log_records(log_path)?;
+    let first_pos = session_position(&records, first)?;
+    let second_pos = session_position(&records, first + 1)?;
+
+    let a = Session::from_record(&records[first_pos])?;
+    let b = Session::from_record(&records[second_pos])?;
+
+    let start = a.start.min(b.start);
+    let stop = a.stop.max(b.stop);
+    let elapsed_seconds = if include_gap {
+        stop.signed_duration_since(start).num_seconds()
+    } else {
+        a.elapsed_seconds + b.elapsed_seconds
+    };
+
+    let merged = Session { start, stop, elapsed_seconds, ..a };
+
+    // Any comments between the two are kept, after the merged session.
+    records[first_pos] = StringRecord::from(merged.to_record());
+    records.remove(second_pos);
+    rewrite_log(log_path, &records)?;
+
+    say!("Sessions merged!");
+    print_start_time(cfg, merged.start);
+    print_stop_time(cfg, merged.stop);
+    print_elapsed_time(cfg, Duration::seconds(merged.elapsed_seconds));
+    print_description(cfg, &merged.description);
+    print_tags(&merged.tags.join(" "));
+
+    Ok(())
+}
+
+/// Replaces every session in the log with `records`. The new log is written
+/// next to the old one and swapped in, so a crash part way through never
+/// leaves a truncated log behind. Every command that rewrites the log goes
+/// through here.
+fn rewrite_log(log_path: &str, records: &[StringRecord]) -> Result<(), Box<dyn Error>> {
+    let header = StringRecord::from(LOG_HEADER.to_vec());
+    replace_file(log_path, std::iter::once(&header).chain(records))
+}
+
+/// Replaces the file at `path` with `records`. They are written and synced
+/// to a file beside it which is then renamed into place, so a failure at
+/// any point leaves the original untouched.
+fn replace_file<'a>(path: &str, records: impl IntoIterator<Item = &'a StringRecord>) -> Result<(), Box<dyn Error>> {
+    let new_path = format!("{}.new", path);
+
+    verbose("writing", &new_path);
+    let replaced = write_records(&new_path, records)
+        .and_then(|()| {
+            verbose(&format!("renaming {} over", new_path), path);
+            Ok(rename(&new_path, path)?)
+        });
+    if replaced.is_err() {
+        verbose("removing", &new_path);
+        let _ = remove_file(&new_path);
+    }
+
+    replaced
+}
+
+fn write_records<'a>(path: &str, records: impl IntoIterator<Item = &'a StringRecord>) -> Result<(), Box<dyn Error>> {
+    // Rows logged before the category column existed are one field short,
+    // and pause markers are shorter than the session row they follow.
+    let mut writer = WriterBuilder::new().flexible(true).from_path(path)?;
+    for record in records {
+        writer.write_record(record)?;
+    }
+
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Where CSV output goes: stdout, or nowhere with `--quiet`.
+fn stdout() -> Box<dyn io::Write> {
+    if QUIET.load(Ordering::Relaxed) {
+        Box::new(io::sink())
+    } else {
+        Box::new(io::stdout())
+    }
+}
+
+/// Opens `path` for an export. An existing file is only replaced if `force`
+/// is given.
+fn output_file(path: &str, force: bool) -> Result<Box<dyn io::Write>, Box<dyn Error>> {
+    verbose("writing", path);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(force)
+        .truncate(force)
+        .create_new(!force)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => format!("{} already exists; pass --force to overwrite it", path),
+            _ => format!("Could not open {}: {}", path, e),
+        })?;
+
+    Ok(Box::new(BufWriter::new(file)))
+}
+
+/// Describes a file operation on stderr when `--verbose` is given.
+fn verbose<P: AsRef<Path>>(op: &str, path: P) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        eprintln!("{} {}", op, path.as_ref().display());
+    }
+}
+
+/// Shortens `s` to at most `width` characters, ending in an ellipsis if
+/// anything was cut, or leaves it alone if `width` is 0. Counts characters
+/// rather than bytes, so multibyte text is never split.
+fn truncate(s: &str, width: usize) -> String {
+    if width == 0 || s.chars().count() <= width {
+        return s.to_string();
+    }
+
+    let mut short: String = s.chars().take(width.saturating_sub(1)).collect();
+    short.push('…');
+    short
+}
+
+/// Splits a number of seconds into hours, minutes and seconds.
+fn hms(total: i64) -> (i64, i64, i64) {
+    (total / 3600, total / 60 % 60, total % 60)
+}
+
+fn format_duration(seconds: i64, style: DurationStyle) -> String {
+    let (hrs, min, sec) = hms(seconds);
+    match style {
+        DurationStyle::Words => format!("{} hour(s), {} minute(s), {} second(s)", hrs, min, sec),
+        DurationStyle::Clock => format!("{:02}:{:02}:{:02}", hrs, min, sec),
+    }
+}
+
+/// Highlights `s` in the configured colour, unless colour is disabled.
+fn paint(cfg: &Config, s: String) -> String {
+    if cfg.colored {
+        Colour::Fixed(cfg.colour).paint(s).to_string()
+    } else {
+        s
+    }
+}
+
+/// Highlights `s` as a warning, unless colour is disabled.
+fn warn(cfg: &Config, s: String) -> String {
+    if cfg.colored {
+        Colour::Yellow.bold().paint(s).to_string()
+    } else {
+        s
+    }
+}
+
+/// De-emphasizes `s`, unless colour is disabled.
+fn dim(cfg: &Config, s: String) -> String {
+    if cfg.colored {
+        Style::new().dimmed().paint(s).to_string()
+    } else {
+        s
+    }
+}
+
+fn print_progress(cfg: &Config, total: i64, goal: i64) {
+    let percent = if goal > 0 { total * 100 / goal } else { 100 };
+    let filled = (BAR_WIDTH * percent.min(100) / 100) as usize;
+    let empty = BAR_WIDTH as usize - filled;
+
+    say!("[{}{}] {}%",
+        paint(cfg, "#".repeat(filled)),
+        "-".repeat(empty),
+        percent);
+}
+
+fn print_goal(cfg: &Config, total: i64, goal: i64) {
+    let percent = if goal > 0 { total * 100 / goal } else { 100 };
+    let (hrs, min, _) = hms(total);
+    let (goal_hrs, goal_min, _) = hms(goal);
+
+    say!("Today: {} / {}h{:02}m goal ({}%)",
+        paint(cfg, format!("{}h{:02}m", hrs, min)),
+        goal_hrs, goal_min,
+        percent);
+}
+
+fn print_start_time<T: TimeZone>(cfg: &Config, time: DateTime<T>) where
+    T::Offset: Display
+{
+    say!("Start: {}",
+        paint(cfg, time.format(&cfg.time_fmt).to_string()));
+}
+
+fn print_stop_time<T: TimeZone>(cfg: &Config, time: DateTime<T>) where
+    T::Offset: Display
+{
+    say!("Stop: {}",
+        paint(cfg, time.format(&cfg.time_fmt).to_string()));
+}
+
+fn print_elapsed_time(cfg: &Config, elapsed: Duration) {
+    say!("Time Elapsed: {}",
+        paint(cfg, format_duration(elapsed.num_seconds(), cfg.duration_style)));
+}
+
+/// Prints the description with any further lines indented under the
+/// first, or with `truncate`, just the first line and a count of the rest.
+fn print_description(cfg: &Config, desc: &str) {
+    let mut lines = desc.lines();
+    let first = match lines.next() {
+        Some(first) => first,
+        None => return,
+    };
+
+    if cfg.truncate {
+        match lines.count() {
+            0 => say!("Description: {}", first),
+            more => say!("Description: {} ({} more line(s))", first, more),
+        }
+    } else {
+        say!("Description: {}", first);
+        for line in lines {
+            say!("             {}", line);
+        }
+    }
+}
+
+fn print_tags(tags: &str) {
+    if !tags.is_empty() {
+        say!("Tags: {}", tags);
+    }
+}
+
+fn handle_tag(tmp_path: &str, add: Vec<&str>, remove: Vec<&str>) -> Result<(), Box<dyn Error>> {
+    if !Path::new(tmp_path).is_file() {
+        say!("No active deep work session");
+        return Ok(());
+    }
+
+    for tag in &add {
+        validate_tag(tag)?;
+    }
+
+    verbose("reading tmp file", tmp_path);
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(tmp_path)?;
+    let mut records = reader.records().collect::<Result<Vec<_>, _>>()?;
+
+    let record = match records.iter_mut().find(|r| !is_marker(r)) {
+        Some(record) if record.len() >= 3 => record,
+        Some(_) => return Err(CORRUPT_TMP.into()),
+        None => return Err(EMPTY_TMP.into()),
+    };
+
+    let mut tags: Vec<&str> = Vec::new();
+    for tag in record[2].split_whitespace().chain(add) {
+        if !remove.contains(&tag) && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    let tags = tags.join(" ");
+
+    let edited: StringRecord = record.iter()
+        .enumerate()
+        .map(|(i, field)| if i == 2 { tags.as_str() } else { field })
+        .collect();
+    *record = edited;
+
+    replace_file(tmp_path, &records)?;
+
+    say!("Tags updated!");
+    print_tags(&tags);
+
+    Ok(())
+}
+
+fn print_category(category: &str) {
+    if !category.is_empty() {
+        say!("Category: {}", category);
+    }
+}
+
+/// Returns whether a session is active.
+fn handle_status(cfg: &Config, tmp_path: &str, format: StatusFormat, max_hours: f64) -> Result<bool, Box<dyn Error>> {
+    let path = Path::new(tmp_path);
+
+    if !path.is_file() {
+        match format {
+            StatusFormat::Text => say!("No active deep work session"),
+            StatusFormat::Short => {}
+            StatusFormat::Json => say!("{}", serde_json::json!({ "active": false })),
+            StatusFormat::Seconds => say!("0"),
+        }
+        return Ok(false);
+    }
+
+    let now = Local::now();
+    let record = match datetime_from_last_entry(tmp_path)? {
+        Some(record) => record,
+        None => return unreadable_status(format, EMPTY_TMP),
+    };
+    let start = match DateTime::parse_from_rfc3339(&record[0]) {
+        Ok(start) => start,
+        Err(_) => return unreadable_status(format, CORRUPT_TMP),
+    };
+    let desc = &record[1];
+    let tags = &record[2];
+    let category = record.get(3).unwrap_or("");
+    let (paused, is_paused) = paused_time(tmp_path, now)?;
+    let elapsed = now.signed_duration_since(start) - paused;
+
+    if format == StatusFormat::Seconds {
+        say!("{}", elapsed.num_seconds());
+        return Ok(true);
+    }
+
+    if format == StatusFormat::Json {
+        say!("{}", serde_json::json!({
+            "active": true,
+            "paused": is_paused,
+            "start": start,
+            "elapsed_seconds": elapsed.num_seconds(),
+            "description": desc,
+            "tags": tags.split_whitespace().collect::<Vec<_>>(),
+            "category": Some(category).filter(|c| !c.is_empty()),
+        }));
+        return Ok(true);
+    }
+
+    if format == StatusFormat::Short {
+        let (hrs, min, sec) = hms(elapsed.num_seconds());
+        let clock = format!("{:02}:{:02}:{:02}", hrs, min, sec);
+        if tags.is_empty() {
+            say!("{}", clock);
+        } else {
+            say!("{} {}", tags, clock);
+        }
+        return Ok(true);
+    }
+
+    if is_paused {
+        say!("Deep work session is paused");
+    }
+    print_start_time(cfg, start);
+    print_elapsed_time(cfg, elapsed);
+    print_description(cfg, desc);
+    print_tags(tags);
+    print_category(category);
+    for (at, note) in session_notes(tmp_path)? {
+        say!("Note: {} {}", paint(cfg, at.format(&cfg.time_fmt).to_string()), note);
+    }
+
+    if elapsed.num_seconds() as f64 > max_hours * 3600.0 {
+        say!("{}", warn(cfg, format!("⚠ session running over {}h — did you forget to stop?", max_hours)));
+    }
+
+    Ok(true)
+}
+
+/// Reports an active session file that can't be read. It's an error, so it
+/// reaches stderr even with --quiet. `--json` also gets an object on stdout
+/// so a script reading it still has something to parse, while `--short` and
+/// `--seconds` print nothing there, leaving a status bar blank.
+fn unreadable_status(format: StatusFormat, problem: &str) -> Result<bool, Box<dyn Error>> {
+    if format == StatusFormat::Json {
+        say!("{}", serde_json::json!({ "active": false, "error": problem }));
+    }
+    Err(problem.into())
+}
+
+/// Redraws the status every second until the session's file goes away,
+/// e.g. because it was stopped from another terminal. Returns whether a
+/// session was active to begin with.
+fn handle_status_watch(cfg: &Config, tmp_path: &str, max_hours: f64) -> Result<bool, Box<dyn Error>> {
+    let mut ticks = 0;
+
+    loop {
+        if ticks > 0 && !Path::new(tmp_path).is_file() {
+            say!("Deep work session ended");
+            return Ok(true);
+        }
+
+        // Clear the screen and move the cursor home before each redraw.
+        write!(stdout(), "\x1b[2J\x1b[H")?;
+        match handle_status(cfg, tmp_path, StatusFormat::Text, max_hours) {
+            Ok(true) => {}
+            Ok(false) => return Ok(ticks > 0),
+            // The session may be stopped between the check and the read.
+            Err(_) if ticks > 0 && !Path::new(tmp_path).is_file() => continue,
+            Err(e) => return Err(e),
+        }
+        io::stdout().flush()?;
+
+        ticks += 1;
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+/// Returns whether any session is active.
+fn handle_status_all(cfg: &Config, dir: &Path, profile: Option<&str>, max_hours: f64) -> Result<bool, Box<dyn Error>> {
+    let unnamed = tmp_file(dir, profile, None);
+    let unnamed = unnamed.file_name().map(|f| f.to_string_lossy()).unwrap_or_default();
+    let prefix = match profile {
+        Some(profile) => format!(".dw.{}.session.", profile),
+        None => ".dw.session.".to_string(),
+    };
+    let mut names = Vec::new();
+
+    verbose("scanning for tmp files in", dir);
+    for entry in read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        if file_name == unnamed {
+            names.push(None);
+        } else if let Some(name) = file_name.strip_prefix(&prefix).and_then(|n| n.strip_suffix(".tmp")) {
+            // A profile called `session` has named sessions that look like
+            // `session.name` here.
+            if !name.contains('.') {
+                names.push(Some(name.to_string()));
+            }
+        }
+    }
+
+    if names.is_empty() {
+        say!("No active deep work session");
+        return Ok(false);
+    }
+
+    names.sort();
+
+    for (i, name) in names.iter().enumerate() {
+        if i > 0 {
+            say!();
+        }
+        say!("Session: {}", paint(cfg, name.as_deref().unwrap_or("(unnamed)").to_string()));
+
+        let path = tmp_file(dir, profile, name.as_deref());
+        let path_str = path.to_str()
+            .expect("Failed to convert tmp path to string");
+        handle_status(cfg, path_str, StatusFormat::Text, max_hours)?;
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(fields: &[&str]) -> StringRecord {
+        StringRecord::from(fields.to_vec())
+    }
+
+    fn config() -> Config {
+        Config {
+            colour: TXT_COLOUR,
+            colored: false,
+            time_fmt: TIME_FMT.to_string(),
+            date_fmt: DATE_FMT.to_string(),
+            daily_goal: None,
+            warn_after_hour: None,
+            duration_style: DurationStyle::Words,
+            tag_aliases: HashMap::new(),
+            truncate: false,
+        }
+    }
+
+    fn stop_options() -> StopOptions {
+        StopOptions { min_duration: 0, round: None, notify: false, prompt: false, keep_tmp: false }
+    }
+
+    /// An empty directory of its own for a test to work in.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("dw-test-{}-{}", process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn session_round_trips_through_a_record() {
+        let fields = ["2024-05-01T09:00:00+02:00", "2024-05-01T10:30:00+02:00", "5400",
+            "Draft chapter 2", "thesis writing", "Work"];
+        let sess = Session::from_record(&record(&fields)).unwrap();
+
+        assert_eq!(sess.elapsed_seconds, 5400);
+        assert_eq!(sess.tags, ["thesis", "writing"]);
+        assert_eq!(sess.category.as_deref(), Some("Work"));
+        assert_eq!(sess.to_record(), fields);
+    }
+
+    #[test]
+    fn session_without_a_category_column_round_trips() {
+        let fields = ["2024-05-01T09:00:00+00:00", "2024-05-01T09:25:00+00:00", "1500",
+            "", "reading"];
+        let sess = Session::from_record(&record(&fields)).unwrap();
+        assert_eq!(sess.category, None);
+
+        // Written back, it gains an empty category column.
+        let written = sess.to_record();
+        assert_eq!(written[..5], fields);
+        assert_eq!(written[5], "");
+
+        let reread = Session::from_record(&StringRecord::from(written)).unwrap();
+        assert_eq!(reread.start, sess.start);
+        assert_eq!(reread.stop, sess.stop);
+        assert_eq!(reread.elapsed_seconds, sess.elapsed_seconds);
+        assert_eq!(reread.description, sess.description);
+        assert_eq!(reread.tags, sess.tags);
+        assert_eq!(reread.category, None);
+    }
+
+    #[test]
+    fn hms_splits_seconds() {
+        assert_eq!(hms(0), (0, 0, 0));
+        assert_eq!(hms(3661), (1, 1, 1));
+        assert_eq!(hms(7325), (2, 2, 5));
+    }
+
+    #[test]
+    fn failed_rewrite_leaves_the_log_untouched() {
+        let dir = scratch_dir("failed-rewrite");
+        let log = dir.join(DW_LOG);
+        let log_str = log.to_str().unwrap();
+        let original = "start,stop,elapsed_seconds,description,tags,category\n\
+            2024-05-01T09:00:00+00:00,2024-05-01T10:00:00+00:00,3600,Kept,,\n";
+        std::fs::write(&log, original).unwrap();
+
+        // A directory where the new log would be written makes that fail.
+        std::fs::create_dir(format!("{}.new", log_str)).unwrap();
+
+        assert!(rewrite_log(log_str, &[]).is_err());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), original);
+    }
+
+    #[test]
+    fn elapsed_time_spans_a_daylight_saving_change() {
+        let dir = scratch_dir("dst");
+        let log = dir.join(DW_LOG);
+        let tmp = dir.join(DW_TMP);
+        let (log_str, tmp_str) = (log.to_str().unwrap(), tmp.to_str().unwrap());
+
+        // US Eastern springs forward at 02:00 on 2024-03-10, so 01:30 EST to
+        // 03:30 EDT looks like two hours on the clock but is only one.
+        std::fs::write(&tmp, "2024-03-10T01:30:00-05:00,,,,\n").unwrap();
+        let stop = DateTime::parse_from_rfc3339("2024-03-10T03:30:00-04:00").unwrap();
+
+        assert!(handle_stop(&config(), log_str, tmp_str, &stop_options(),
+            stop.with_timezone(&Local), false).unwrap());
+
+        let sess = log_sessions(log_str).unwrap().next().unwrap().unwrap();
+        assert_eq!(sess.elapsed_seconds, 3600);
+    }
+
+    #[test]
+    fn truncate_counts_characters_not_bytes() {
+        let desc = "🚀 Ünïcödé résumé 📚";
+        for width in 1..=4 {
+            let short = truncate(desc, width);
+            assert_eq!(short.chars().count(), width);
+            assert!(short.ends_with('…'));
+        }
+        assert_eq!(truncate(desc, 2), "🚀…");
+        assert_eq!(truncate(desc, 0), desc);
+        assert_eq!(truncate(desc, desc.chars().count()), desc);
+    }
+
+    #[test]
+    fn only_one_of_two_concurrent_starts_starts_a_session() {
+        let dir = scratch_dir("lock");
+        let log = dir.join(DW_LOG);
+        let tmp = dir.join(DW_TMP);
+        let start = |desc: &str| {
+            let opts = StartOptions { category: "", pomodoro: None, at: Local::now(), resume_within: None };
+            start_session(&config(), log.to_str().unwrap(), tmp.to_str().unwrap(), desc, vec![], &opts, false)
+        };
+
+        // Another dw holding the lock keeps start from doing anything.
+        let held = lock(&dir, 0).unwrap();
+        assert!(start("blocked").is_err());
+        assert!(!tmp.exists());
+        drop(held);
+
+        let ready = std::sync::Arc::new(std::sync::Barrier::new(2));
+        let racers: Vec<_> = (0..2).map(|i| {
+            let (log, tmp, ready) = (log.clone(), tmp.clone(), ready.clone());
+            thread::spawn(move || {
+                let opts = StartOptions { category: "", pomodoro: None, at: Local::now(), resume_within: None };
+                let desc = format!("racer {}", i);
+                ready.wait();
+                // The loser either finds the lock busy or, once it's free,
+                // the winner's session already there.
+                start_session(&config(), log.to_str().unwrap(), tmp.to_str().unwrap(),
+                    &desc, vec![], &opts, false).unwrap_or(false)
+            })
+        }).collect();
+
+        let started: Vec<bool> = racers.into_iter().map(|r| r.join().unwrap()).collect();
+        assert_eq!(started.iter().filter(|&&s| s).count(), 1);
+        assert_eq!(std::fs::read_to_string(&tmp).unwrap().lines().count(), 1);
+        assert!(lock(&dir, 0).is_ok());
+    }
+
+    #[test]
+    fn empty_tmp_file_is_an_error_not_a_panic() {
+        let dir = scratch_dir("empty-tmp");
+        let log = dir.join(DW_LOG);
+        let tmp = dir.join(DW_TMP);
+        let (log_str, tmp_str) = (log.to_str().unwrap(), tmp.to_str().unwrap());
+        std::fs::write(&tmp, "").unwrap();
+
+        assert!(datetime_from_last_entry(tmp_str).unwrap().is_none());
+        let err = handle_status(&config(), tmp_str, StatusFormat::Text, 0.0).unwrap_err();
+        assert_eq!(err.to_string(), EMPTY_TMP);
+        let err = handle_stop(&config(), log_str, tmp_str, &stop_options(), Local::now(), false).unwrap_err();
+        assert_eq!(err.to_string(), EMPTY_TMP);
+        assert!(handle_pause(&config(), tmp_str).is_err());
+        assert!(handle_resume(&config(), tmp_str).is_err());
+        assert!(handle_note(&config(), tmp_str, "hi").is_err());
+
+        // Nothing is logged or added, and the file is left for `cancel`.
+        assert!(!log.exists());
+        assert_eq!(std::fs::read_to_string(&tmp).unwrap(), "");
+    }
+
+    #[test]
+    fn durations_past_i32_max_add_up() {
+        let big = 1_i64 << 31;
+        let row = |start: &str, stop: &str| record(&[start, stop, &big.to_string(), "", ""]);
+        let total: i64 = [
+            row("1950-01-01T00:00:00+00:00", "2018-01-20T03:14:08+00:00"),
+            row("1960-01-01T00:00:00+00:00", "2028-01-20T03:14:08+00:00"),
+        ].iter()
+            .map(|r| Session::from_record(r).unwrap().elapsed_seconds)
+            .sum();
+
+        assert_eq!(total, 2 * big);
+        assert!(total > i64::from(i32::MAX));
+        assert_eq!(hms(total), (1_193_046, 28, 16));
+        assert_eq!(format_duration(total, DurationStyle::Clock), "1193046:28:16");
+        assert_eq!(format_duration(big, DurationStyle::Words),
+            "596523 hour(s), 14 minute(s), 8 second(s)");
+    }
+
+    #[test]
+    fn description_with_commas_survives_start_and_stop() {
+        let dir = scratch_dir("commas");
+        let log = dir.join(DW_LOG);
+        let tmp = dir.join(DW_TMP);
+        let (log_str, tmp_str) = (log.to_str().unwrap(), tmp.to_str().unwrap());
+        let desc = "fix bug, then test";
+        let opts = StartOptions {
+            category: "",
+            pomodoro: None,
+            at: Local::now() - Duration::minutes(30),
+            resume_within: None,
+        };
+
+        handle_start(&config(), tmp_str, desc, vec![], &opts, false).unwrap();
+        assert_eq!(&datetime_from_last_entry(tmp_str).unwrap().unwrap()[1], desc);
+
+        handle_stop(&config(), log_str, tmp_str, &stop_options(), Local::now(), false).unwrap();
+        let sess = log_sessions(log_str).unwrap().next().unwrap().unwrap();
+        assert_eq!(sess.description, desc);
+    }
+
+    /// Run with `cargo test --release -- --ignored` to check that a large
+    /// log streams through in reasonable time.
+    #[test]
+    #[ignore]
+    fn streams_a_large_log() {
+        let dir = scratch_dir("large-log");
+        let log = dir.join(DW_LOG);
+        let first = DateTime::parse_from_rfc3339("2000-01-01T09:00:00+00:00").unwrap();
+        let rows = 100_000;
+
+        let mut writer = Writer::from_path(&log).unwrap();
+        writer.write_record(LOG_HEADER).unwrap();
+        for i in 0..rows {
+            // Three sessions a day.
+            let start = first + Duration::hours(8 * i);
+            let sess = Session {
+                start,
+                stop: start + Duration::hours(1),
+                elapsed_seconds: 3600,
+                description: format!("Session {}", i),
+                tags: vec!["bench".to_string()],
+                category: None,
+            };
+            writer.write_record(sess.to_record()).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let log_str = log.to_str().unwrap();
+        let began = time::Instant::now();
+        let days = daily_totals(log_str, None).unwrap();
+        handle_stats(&config(), log_str).unwrap();
+        handle_report(&config(), log_str, 7).unwrap();
+        let took = began.elapsed();
+
+        // What stats and report keep in memory is a total per day, a third
+        // as many entries as there are sessions.
+        assert_eq!(days.len() as i64, (rows + 2) / 3);
+        assert_eq!(days.values().sum::<i64>(), rows * 3600);
+        assert!(took < time::Duration::from_secs(10), "took {:?}", took);
+    }
+}